@@ -0,0 +1,658 @@
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+use stivale_boot::v2::StivaleStruct;
+
+use crate::{
+    mm::{PhysAddr, PhysMem, PhysicalMemory},
+    sync::LockCell,
+};
+
+const SDT_HEADER_SIZE: usize = 36;
+
+pub static MADT: LockCell<Option<Madt>> = LockCell::new(None);
+pub static HPET: LockCell<Option<Hpet>> = LockCell::new(None);
+pub static FADT: LockCell<Option<Fadt>> = LockCell::new(None);
+pub static MCFG: LockCell<Option<Mcfg>> = LockCell::new(None);
+
+// Reads a `T` out of a table at `offset`, tables make no promises about alignment
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(core::mem::size_of::<T>())?;
+    if end > bytes.len() {
+        return None;
+    }
+
+    Some(unsafe { core::ptr::read_unaligned(bytes[offset..].as_ptr() as *const T) })
+}
+
+// ACPI structures are valid when all of their bytes sum to zero
+pub fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) == 0
+}
+
+// Checks the header of an SDT and trims it to the length it claims
+pub fn validate_sdt(table: &[u8]) -> Option<&[u8]> {
+    let length = read::<u32>(table, 4)? as usize;
+    if length < SDT_HEADER_SIZE || length > table.len() {
+        return None;
+    }
+
+    let table = &table[..length];
+    if !checksum(table) {
+        log::warn!(
+            "ACPI table {} has a bad checksum",
+            core::str::from_utf8(&table[0..4]).unwrap_or("????")
+        );
+        return None;
+    }
+
+    Some(table)
+}
+
+// Maps a whole SDT given its physical address, using the length from its header
+unsafe fn table_at(phys: u64) -> Option<&'static [u8]> {
+    let header = PhysicalMemory.translate(PhysAddr(phys as usize), SDT_HEADER_SIZE)?;
+    let length = core::ptr::read_unaligned(header.add(4) as *const u32) as usize;
+
+    if length < SDT_HEADER_SIZE {
+        return None;
+    }
+
+    let table = PhysicalMemory.translate(PhysAddr(phys as usize), length)?;
+    Some(core::slice::from_raw_parts(table, length))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub rsdt_address: u32,
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    pub fn parse(bytes: &[u8]) -> Option<Rsdp> {
+        if bytes.get(0..8)? != b"RSD PTR " || !checksum(bytes.get(0..20)?) {
+            return None;
+        }
+
+        let revision: u8 = read(bytes, 15)?;
+        let xsdt_address = if revision >= 2 {
+            let length = read::<u32>(bytes, 20)? as usize;
+            if !checksum(bytes.get(0..length)?) {
+                return None;
+            }
+
+            Some(read(bytes, 24)?)
+        } else {
+            None
+        };
+
+        Some(Rsdp {
+            revision,
+            rsdt_address: read(bytes, 16)?,
+            xsdt_address,
+        })
+    }
+
+    // Walks the XSDT, or the RSDT on ACPI 1.0, looking for `signature`. `map`
+    // turns a physical address into the bytes of the table found there.
+    pub fn find_table<'a>(
+        &self,
+        map: impl Fn(u64) -> Option<&'a [u8]>,
+        signature: &[u8; 4],
+    ) -> Option<&'a [u8]> {
+        let (root, entry_size) = match self.xsdt_address {
+            Some(xsdt) => (validate_sdt(map(xsdt)?)?, 8),
+            None => (validate_sdt(map(self.rsdt_address as u64)?)?, 4),
+        };
+
+        let entries = (root.len() - SDT_HEADER_SIZE) / entry_size;
+        for i in 0..entries {
+            let offset = SDT_HEADER_SIZE + i * entry_size;
+            let phys = if entry_size == 8 {
+                read::<u64>(root, offset)?
+            } else {
+                read::<u32>(root, offset)? as u64
+            };
+
+            let table = match map(phys) {
+                Some(table) => table,
+                None => continue,
+            };
+
+            if table.get(0..4) == Some(&signature[..]) {
+                return validate_sdt(table);
+            }
+        }
+
+        None
+    }
+}
+
+// Generic Address Structure, describes a register in some address space
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    fn parse(bytes: &[u8], offset: usize) -> Option<GenericAddress> {
+        Some(GenericAddress {
+            address_space: read(bytes, offset)?,
+            bit_width: read(bytes, offset + 1)?,
+            bit_offset: read(bytes, offset + 2)?,
+            access_size: read(bytes, offset + 3)?,
+            address: read(bytes, offset + 4)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicInfo {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+}
+
+impl LocalApicInfo {
+    pub fn usable(&self) -> bool {
+        // Enabled, or online capable
+        self.flags & 0x3 != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub flags: u32,
+    pub local_apics: Vec<LocalApicInfo>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    pub fn parse(table: &[u8]) -> Option<Madt> {
+        if table.get(0..4)? != b"APIC" {
+            return None;
+        }
+
+        let mut madt = Madt {
+            local_apic_address: read::<u32>(table, SDT_HEADER_SIZE)? as u64,
+            flags: read(table, SDT_HEADER_SIZE + 4)?,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset + 2 <= table.len() {
+            let typ: u8 = read(table, offset)?;
+            let len: u8 = read(table, offset + 1)?;
+            if len < 2 {
+                return None;
+            }
+
+            let entry = table.get(offset..offset + len as usize)?;
+
+            match typ {
+                0 => madt.local_apics.push(LocalApicInfo {
+                    processor_id: read(entry, 2)?,
+                    apic_id: read(entry, 3)?,
+                    flags: read(entry, 4)?,
+                }),
+                1 => madt.io_apics.push(IoApicInfo {
+                    id: read(entry, 2)?,
+                    address: read(entry, 4)?,
+                    gsi_base: read(entry, 8)?,
+                }),
+                2 => madt.overrides.push(InterruptOverride {
+                    bus: read(entry, 2)?,
+                    source: read(entry, 3)?,
+                    gsi: read(entry, 4)?,
+                    flags: read(entry, 8)?,
+                }),
+                5 => madt.local_apic_address = read(entry, 4)?,
+                _ => {}
+            }
+
+            offset += len as usize;
+        }
+
+        Some(madt)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub fn parse(table: &[u8]) -> Option<Hpet> {
+        if table.get(0..4)? != b"HPET" {
+            return None;
+        }
+
+        Some(Hpet {
+            event_timer_block_id: read(table, 36)?,
+            address: GenericAddress::parse(table, 40)?,
+            hpet_number: read(table, 52)?,
+            minimum_tick: read(table, 53)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub iapc_boot_arch: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    pub century_register: u8,
+}
+
+impl Fadt {
+    // IA-PC boot architecture flag for an 8042 keyboard controller
+    pub const BOOT_ARCH_8042: u16 = 1 << 1;
+
+    // The PM timer is 32 bits wide instead of 24
+    pub const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+    pub const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+    pub fn parse(table: &[u8]) -> Option<Fadt> {
+        if table.get(0..4)? != b"FACP" {
+            return None;
+        }
+
+        // The reset register only exists from revision 2 onwards, older tables
+        // are shorter than that
+        let flags: u32 = read(table, 112)?;
+        let reset_register =
+            GenericAddress::parse(table, 116).filter(|_| flags & Self::FLAG_RESET_REG_SUP != 0);
+
+        Some(Fadt {
+            sci_interrupt: read(table, 46)?,
+            smi_command_port: read(table, 48)?,
+            acpi_enable: read(table, 52)?,
+            acpi_disable: read(table, 53)?,
+            pm1a_control_block: read(table, 64)?,
+            pm1b_control_block: read(table, 68)?,
+            pm_timer_block: read(table, 76)?,
+            iapc_boot_arch: read(table, 109).unwrap_or(0),
+            flags,
+            reset_register,
+            reset_value: read(table, 128).unwrap_or(0),
+            century_register: read(table, 108)?,
+        })
+    }
+
+    pub fn pm_timer_32bit(&self) -> bool {
+        self.flags & Self::FLAG_TMR_VAL_EXT != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+#[derive(Debug)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    pub fn parse(table: &[u8]) -> Option<Mcfg> {
+        if table.get(0..4)? != b"MCFG" {
+            return None;
+        }
+
+        // 8 reserved bytes after the header, then 16 byte entries
+        let entries = (SDT_HEADER_SIZE + 8..table.len())
+            .step_by(16)
+            .map(|offset| {
+                Some(McfgEntry {
+                    base_address: read(table, offset)?,
+                    segment_group: read(table, offset + 8)?,
+                    start_bus: read(table, offset + 10)?,
+                    end_bus: read(table, offset + 11)?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Mcfg { entries })
+    }
+}
+
+pub fn init(boot_info: &'static StivaleStruct) -> Option<()> {
+    let rsdp = boot_info.rsdp()?.rsdp as usize;
+    let rsdp = Rsdp::parse(unsafe { core::slice::from_raw_parts(rsdp as *const u8, 36) })?;
+
+    let find = |signature| rsdp.find_table(|phys| unsafe { table_at(phys) }, signature);
+
+    // Only the MADT is mandatory, the rest depends on the machine
+    let madt = Madt::parse(find(b"APIC")?)?;
+    let hpet = find(b"HPET").and_then(Hpet::parse);
+    let fadt = find(b"FACP").and_then(Fadt::parse);
+    let mcfg = find(b"MCFG").and_then(Mcfg::parse);
+
+    log::info!(
+        "ACPI revision {}: {} local APICs, {} IO-APICs, {} interrupt overrides, HPET: {}, FADT: {}, MCFG: {}",
+        rsdp.revision,
+        madt.local_apics.len(),
+        madt.io_apics.len(),
+        madt.overrides.len(),
+        hpet.is_some(),
+        fadt.is_some(),
+        mcfg.is_some()
+    );
+
+    *MADT.lock() = Some(madt);
+    *HPET.lock() = hpet;
+    *FADT.lock() = fadt;
+    *MCFG.lock() = mcfg;
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_sdt, Fadt, GenericAddress, Hpet, Madt, Mcfg, Rsdp};
+    use alloc::vec::Vec;
+
+    // Tables laid out like the ones QEMU builds for `-M q35 -smp 2`, as OVMF
+    // hands them on with an XSDT. The i440fx FADT is the short revision 1 one
+    const RSDP_V1: [u8; 20] = [
+        0x52, 0x53, 0x44, 0x20, 0x50, 0x54, 0x52, 0x20, 0xc5, 0x42, 0x4f, 0x43, 0x48, 0x53, 0x20,
+        0x00, 0x00, 0x10, 0xfe, 0x7f,
+    ];
+
+    const RSDP_V2: [u8; 36] = [
+        0x52, 0x53, 0x44, 0x20, 0x50, 0x54, 0x52, 0x20, 0xc3, 0x42, 0x4f, 0x43, 0x48, 0x53, 0x20,
+        0x02, 0x00, 0x10, 0xfe, 0x7f, 0x24, 0x00, 0x00, 0x00, 0x00, 0x11, 0xfe, 0x7f, 0x00, 0x00,
+        0x00, 0x00, 0x4e, 0x00, 0x00, 0x00,
+    ];
+
+    const RSDT: [u8; 52] = [
+        0x52, 0x53, 0x44, 0x54, 0x34, 0x00, 0x00, 0x00, 0x01, 0x4f, 0x42, 0x4f, 0x43, 0x48, 0x53,
+        0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20, 0x01, 0x00, 0x00, 0x00, 0x42, 0x58,
+        0x50, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x40, 0xfe, 0x7f, 0x00, 0x20, 0xfe, 0x7f, 0x00,
+        0x30, 0xfe, 0x7f, 0x00, 0x50, 0xfe, 0x7f,
+    ];
+
+    const XSDT: [u8; 68] = [
+        0x58, 0x53, 0x44, 0x54, 0x44, 0x00, 0x00, 0x00, 0x01, 0x39, 0x42, 0x4f, 0x43, 0x48, 0x53,
+        0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20, 0x01, 0x00, 0x00, 0x00, 0x42, 0x58,
+        0x50, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x40, 0xfe, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x20, 0xfe, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0xfe, 0x7f, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x50, 0xfe, 0x7f, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const MADT: [u8; 128] = [
+        0x41, 0x50, 0x49, 0x43, 0x80, 0x00, 0x00, 0x00, 0x01, 0x77, 0x42, 0x4f, 0x43, 0x48, 0x53,
+        0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20, 0x01, 0x00, 0x00, 0x00, 0x42, 0x58,
+        0x50, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x08, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x0c, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a, 0x00,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a, 0x00, 0x05, 0x05, 0x00, 0x00, 0x00,
+        0x0d, 0x00, 0x02, 0x0a, 0x00, 0x09, 0x09, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00,
+        0x0a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x02, 0x0a, 0x00, 0x0b, 0x0b, 0x00, 0x00, 0x00,
+        0x0d, 0x00, 0x04, 0x06, 0xff, 0x00, 0x00, 0x01,
+    ];
+
+    const HPET: [u8; 56] = [
+        0x48, 0x50, 0x45, 0x54, 0x38, 0x00, 0x00, 0x00, 0x01, 0xb4, 0x42, 0x4f, 0x43, 0x48, 0x53,
+        0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20, 0x01, 0x00, 0x00, 0x00, 0x42, 0x58,
+        0x50, 0x43, 0x01, 0x00, 0x00, 0x00, 0x01, 0xa2, 0x86, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xd0, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const FADT: [u8; 244] = [
+        0x46, 0x41, 0x43, 0x50, 0xf4, 0x00, 0x00, 0x00, 0x03, 0x95, 0x42, 0x4f, 0x43, 0x48, 0x53,
+        0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20, 0x01, 0x00, 0x00, 0x00, 0x42, 0x58,
+        0x50, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfe, 0x7f, 0x40, 0x00, 0xfe, 0x7f, 0x00,
+        0x00, 0x09, 0x00, 0xb2, 0x00, 0x00, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x04, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x08, 0x06, 0x00, 0x00, 0x20, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x02,
+        0x00, 0x04, 0x10, 0x00, 0x00, 0x00, 0xff, 0x0f, 0xff, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x32, 0x02, 0x00, 0x00, 0xa5, 0x85, 0x00, 0x00, 0x01, 0x08, 0x00, 0x00,
+        0xf9, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const FADT_V1: [u8; 116] = [
+        0x46, 0x41, 0x43, 0x50, 0x74, 0x00, 0x00, 0x00, 0x01, 0x37, 0x42, 0x4f, 0x43, 0x48, 0x53,
+        0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20, 0x01, 0x00, 0x00, 0x00, 0x42, 0x58,
+        0x50, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfe, 0x7f, 0x40, 0x00, 0xfe, 0x7f, 0x00,
+        0x00, 0x09, 0x00, 0xb2, 0x00, 0x00, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x04, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x08, 0x06, 0x00, 0x00, 0x20, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x02,
+        0x00, 0x04, 0x10, 0x00, 0x00, 0x00, 0xff, 0x0f, 0xff, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x32, 0x00, 0x00, 0x00, 0xa5, 0x84, 0x00, 0x00,
+    ];
+
+    const MCFG: [u8; 60] = [
+        0x4d, 0x43, 0x46, 0x47, 0x3c, 0x00, 0x00, 0x00, 0x01, 0x8c, 0x42, 0x4f, 0x43, 0x48, 0x53,
+        0x20, 0x42, 0x58, 0x50, 0x43, 0x20, 0x20, 0x20, 0x20, 0x01, 0x00, 0x00, 0x00, 0x42, 0x58,
+        0x50, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const RSDT_ADDRESS: u64 = 0x7ffe1000;
+    const XSDT_ADDRESS: u64 = 0x7ffe1100;
+
+    fn map(phys: u64) -> Option<&'static [u8]> {
+        match phys {
+            RSDT_ADDRESS => Some(&RSDT),
+            XSDT_ADDRESS => Some(&XSDT),
+            0x7ffe2000 => Some(&MADT),
+            0x7ffe3000 => Some(&HPET),
+            0x7ffe4000 => Some(&FADT),
+            0x7ffe5000 => Some(&MCFG),
+            _ => None,
+        }
+    }
+
+    // A copy of `table` with `f` applied, checksum fixed up again
+    fn patched(table: &[u8], f: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut table = table.to_vec();
+        f(&mut table);
+
+        table[9] = 0;
+        table[9] = table.iter().fold(0u8, |acc, &b| acc.wrapping_sub(b));
+        table
+    }
+
+    #[test_case]
+    fn rsdp_revisions() {
+        let rsdp = Rsdp::parse(&RSDP_V2).unwrap();
+        assert_eq!(rsdp.revision, 2);
+        assert_eq!(rsdp.rsdt_address as u64, RSDT_ADDRESS);
+        assert_eq!(rsdp.xsdt_address, Some(XSDT_ADDRESS));
+
+        let rsdp = Rsdp::parse(&RSDP_V1).unwrap();
+        assert_eq!(rsdp.revision, 0);
+        assert_eq!(rsdp.xsdt_address, None);
+    }
+
+    #[test_case]
+    fn rsdp_rejects_bad_checksums_and_short_input() {
+        let mut bad = RSDP_V2;
+        bad[9] ^= 1;
+        assert!(Rsdp::parse(&bad).is_none());
+
+        // Only the extended checksum covers the XSDT address
+        let mut bad = RSDP_V2;
+        bad[24] ^= 1;
+        assert!(Rsdp::parse(&bad).is_none());
+
+        let mut bad = RSDP_V1;
+        bad[0] = b'X';
+        assert!(Rsdp::parse(&bad).is_none());
+
+        assert!(Rsdp::parse(&RSDP_V1[..19]).is_none());
+        assert!(Rsdp::parse(&RSDP_V2[..30]).is_none());
+    }
+
+    #[test_case]
+    fn find_table_through_xsdt_and_rsdt() {
+        for rsdp in [&RSDP_V2[..], &RSDP_V1[..]] {
+            let rsdp = Rsdp::parse(rsdp).unwrap();
+
+            assert_eq!(rsdp.find_table(map, b"APIC"), Some(&MADT[..]));
+            assert_eq!(rsdp.find_table(map, b"MCFG"), Some(&MCFG[..]));
+            assert_eq!(rsdp.find_table(map, b"SSDT"), None);
+        }
+    }
+
+    #[test_case]
+    fn validate_sdt_checks_length_and_checksum() {
+        assert_eq!(validate_sdt(&HPET), Some(&HPET[..]));
+
+        // Anything past the length in the header is not part of the table
+        let mut padded = HPET.to_vec();
+        padded.extend_from_slice(&[0xff; 8]);
+        assert_eq!(validate_sdt(&padded), Some(&HPET[..]));
+
+        let mut bad = HPET;
+        bad[40] ^= 1;
+        assert!(validate_sdt(&bad).is_none());
+
+        assert!(validate_sdt(&HPET[..HPET.len() - 1]).is_none());
+        assert!(validate_sdt(&HPET[..3]).is_none());
+
+        let short = patched(&HPET, |table| {
+            table[4..8].copy_from_slice(&20u32.to_le_bytes())
+        });
+        assert!(validate_sdt(&short).is_none());
+    }
+
+    #[test_case]
+    fn madt() {
+        let madt = Madt::parse(&MADT).unwrap();
+
+        assert_eq!(madt.local_apic_address, 0xfee00000);
+        assert_eq!(madt.local_apics.len(), 2);
+        assert!(madt.local_apics.iter().all(|apic| apic.usable()));
+        assert_eq!(madt.local_apics[1].apic_id, 1);
+
+        assert_eq!(madt.io_apics.len(), 1);
+        assert_eq!(madt.io_apics[0].address, 0xfec00000);
+        assert_eq!(madt.io_apics[0].gsi_base, 0);
+
+        assert_eq!(madt.overrides.len(), 5);
+        assert_eq!((madt.overrides[0].source, madt.overrides[0].gsi), (0, 2));
+        assert_eq!(madt.overrides[1].flags, 0xd);
+    }
+
+    #[test_case]
+    fn madt_rejects_broken_entries() {
+        // The first local APIC entry claiming a length of 0, then 200
+        let zero = patched(&MADT, |table| table[45] = 0);
+        assert!(Madt::parse(&zero).is_none());
+
+        let long = patched(&MADT, |table| table[45] = 200);
+        assert!(Madt::parse(&long).is_none());
+
+        assert!(Madt::parse(&MADT[..40]).is_none());
+        assert!(Madt::parse(&HPET).is_none());
+    }
+
+    #[test_case]
+    fn hpet() {
+        let hpet = Hpet::parse(&HPET).unwrap();
+
+        assert_eq!(hpet.event_timer_block_id, 0x8086a201);
+        assert_eq!(hpet.address.address_space, GenericAddress::SYSTEM_MEMORY);
+        assert_eq!(hpet.address.address, 0xfed00000);
+
+        assert!(Hpet::parse(&HPET[..50]).is_none());
+    }
+
+    #[test_case]
+    fn fadt() {
+        let fadt = Fadt::parse(&FADT).unwrap();
+
+        assert_eq!(fadt.sci_interrupt, 9);
+        assert_eq!(fadt.smi_command_port, 0xb2);
+        assert_eq!(fadt.pm1a_control_block, 0x604);
+        assert_eq!(fadt.pm_timer_block, 0x608);
+        assert!(fadt.pm_timer_32bit());
+        assert_eq!(fadt.iapc_boot_arch, Fadt::BOOT_ARCH_8042);
+        assert_eq!(fadt.century_register, 0x32);
+
+        let reset = fadt.reset_register.unwrap();
+        assert_eq!(reset.address_space, GenericAddress::SYSTEM_IO);
+        assert_eq!(reset.address, 0xcf9);
+        assert_eq!(fadt.reset_value, 0x0f);
+    }
+
+    #[test_case]
+    fn fadt_revision_1_has_no_reset_register() {
+        // The flag says there is one, but the table ends before it
+        let fadt = Fadt::parse(&FADT_V1).unwrap();
+        assert!(fadt.flags & Fadt::FLAG_RESET_REG_SUP != 0);
+        assert!(fadt.reset_register.is_none());
+        assert_eq!(fadt.reset_value, 0);
+
+        assert!(Fadt::parse(&FADT_V1[..100]).is_none());
+    }
+
+    #[test_case]
+    fn mcfg() {
+        let mcfg = Mcfg::parse(&MCFG).unwrap();
+
+        assert_eq!(mcfg.entries.len(), 1);
+        assert_eq!(mcfg.entries[0].base_address, 0xb0000000);
+        assert_eq!(mcfg.entries[0].segment_group, 0);
+        assert_eq!(
+            (mcfg.entries[0].start_bus, mcfg.entries[0].end_bus),
+            (0, 0xff)
+        );
+
+        // A cut off entry makes the whole table invalid
+        assert!(Mcfg::parse(&MCFG[..MCFG.len() - 8]).is_none());
+    }
+}
@@ -0,0 +1,204 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cpu;
+
+// The APIC register ranges sit below 4GiB, so they are covered by the identity
+// map in every page table and the firmware MTRRs already make them uncacheable
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const REG_ID: usize = 0x20;
+const REG_TPR: usize = 0x80;
+const REG_EOI: usize = 0xb0;
+const REG_SVR: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+// Divide the timer clock by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+static LAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn read(reg: usize) -> u32 {
+    core::ptr::read_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *const u32)
+}
+
+unsafe fn write(reg: usize, val: u32) {
+    core::ptr::write_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *mut u32, val)
+}
+
+// Sets up the local APIC of the bootstrap core, every core shares the same
+// register window
+pub fn init(base: usize) {
+    LAPIC_BASE.store(base, Ordering::SeqCst);
+    enable_local();
+}
+
+// Enables the local APIC of the calling core
+pub fn enable_local() {
+    unsafe {
+        // Globally enable the APIC in case the firmware did not
+        let apic_base = cpu::rdmsr(cpu::IA32_APIC_BASE);
+        cpu::wrmsr(cpu::IA32_APIC_BASE, apic_base | (1 << 11));
+
+        // Accept every priority and software enable with our spurious vector
+        write(REG_TPR, 0);
+        write(REG_SVR, 0x100 | SPURIOUS_VECTOR as u32);
+    }
+}
+
+pub fn id() -> u32 {
+    unsafe { read(REG_ID) >> 24 }
+}
+
+pub fn eoi() {
+    unsafe { write(REG_EOI, 0) }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ipi {
+    Fixed(u8),
+    Nmi,
+}
+
+pub fn send_ipi(apic_id: u32, ipi: Ipi) {
+    let low = match ipi {
+        Ipi::Fixed(vector) => vector as u32,
+        Ipi::Nmi => 0b100 << 8,
+    };
+
+    unsafe {
+        // Writing the low half is what sends it
+        write(REG_ICR_HIGH, apic_id << 24);
+        write(REG_ICR_LOW, low | ICR_ASSERT);
+
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Counts `initial` timer ticks down and raises `vector` when done, over and
+// over if `periodic`
+pub fn start_timer(vector: u8, initial: u32, periodic: bool) {
+    let mode = if periodic { LVT_TIMER_PERIODIC } else { 0 };
+
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        write(REG_LVT_TIMER, vector as u32 | mode);
+        write(REG_TIMER_INITIAL, initial);
+    }
+}
+
+pub fn stop_timer() {
+    unsafe {
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_TIMER_INITIAL, 0);
+    }
+}
+
+pub fn timer_current() -> u32 {
+    unsafe { read(REG_TIMER_CURRENT) }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+pub struct IoApic {
+    pub id: u8,
+    pub gsi_base: u32,
+    pub entries: u32,
+    base: usize,
+}
+
+impl IoApic {
+    pub unsafe fn new(id: u8, base: usize, gsi_base: u32) -> IoApic {
+        let mut ioapic = IoApic {
+            id,
+            gsi_base,
+            entries: 0,
+            base,
+        };
+
+        ioapic.entries = ((ioapic.read(0x01) >> 16) & 0xff) + 1;
+        ioapic
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile(self.base as *mut u32, reg);
+            core::ptr::read_volatile((self.base + 0x10) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, val: u32) {
+        unsafe {
+            core::ptr::write_volatile(self.base as *mut u32, reg);
+            core::ptr::write_volatile((self.base + 0x10) as *mut u32, val);
+        }
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    pub fn set_redirection(
+        &self,
+        gsi: u32,
+        vector: u8,
+        dest_apic: u32,
+        trigger: Trigger,
+        polarity: Polarity,
+        masked: bool,
+    ) {
+        let reg = 0x10 + (gsi - self.gsi_base) * 2;
+
+        let mut low = vector as u32;
+        if polarity == Polarity::ActiveLow {
+            low |= 1 << 13;
+        }
+        if trigger == Trigger::Level {
+            low |= 1 << 15;
+        }
+        if masked {
+            low |= 1 << 16;
+        }
+
+        // Keep the entry masked while it is half written
+        self.write(reg, 1 << 16);
+        self.write(reg + 1, dest_apic << 24);
+        self.write(reg, low);
+    }
+
+    pub fn mask(&self, gsi: u32) {
+        let reg = 0x10 + (gsi - self.gsi_base) * 2;
+        self.write(reg, self.read(reg) | (1 << 16));
+    }
+
+    pub fn mask_all(&self) {
+        for gsi in self.gsi_base..self.gsi_base + self.entries {
+            self.mask(gsi);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use core::fmt;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use stivale_boot::v2::StivaleStruct;
+use xmas_elf::{
+    sections::SectionData,
+    symbol_table::{Entry, Type},
+    ElfFile,
+};
+
+use crate::{
+    cpu,
+    mm::{PhysAddr, PhysicalMemory, VirtAddr},
+    paging::PageTable,
+};
+
+const MAX_FRAMES: usize = 64;
+
+// The kernel ELF as limine loaded it, kept for its symbol table
+static KERNEL_ELF: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static KERNEL_ELF_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+// Finds the kernel's own ELF so traces can have names, fails if limine did not
+// pass it or it has no symbols
+pub fn init(boot_info: &StivaleStruct) -> Option<()> {
+    let file = boot_info.kernel_file_v2()?;
+    let elf = unsafe {
+        core::slice::from_raw_parts(file.kernel_file as *const u8, file.kernel_size as usize)
+    };
+
+    ElfFile::new(elf).ok()?.find_section_by_name(".symtab")?;
+
+    KERNEL_ELF_SIZE.store(elf.len(), Ordering::SeqCst);
+    KERNEL_ELF.store(elf.as_ptr() as *mut u8, Ordering::SeqCst);
+
+    Some(())
+}
+
+fn kernel_elf() -> Option<ElfFile<'static>> {
+    let ptr = KERNEL_ELF.load(Ordering::SeqCst);
+    if ptr.is_null() {
+        return None;
+    }
+
+    let elf = unsafe { core::slice::from_raw_parts(ptr, KERNEL_ELF_SIZE.load(Ordering::SeqCst)) };
+    ElfFile::new(elf).ok()
+}
+
+// The function `addr` is in and how far into it, without allocating so
+// panics can use it
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let elf = kernel_elf()?;
+    let symtab = elf.find_section_by_name(".symtab")?;
+
+    let symbols = match symtab.get_data(&elf).ok()? {
+        SectionData::SymbolTable64(symbols) => symbols,
+        _ => return None,
+    };
+
+    let symbol = symbols.iter().find(|symbol| {
+        symbol.get_type() == Ok(Type::Func)
+            && addr >= symbol.value()
+            && addr - symbol.value() < symbol.size()
+    })?;
+
+    Some((symbol.get_name(&elf).ok()?, addr - symbol.value()))
+}
+
+// Prints a mangled Rust symbol the way it was written, anything that does not
+// look like one is printed as is
+pub struct Demangle<'a>(pub &'a str);
+
+// Takes the next length prefixed identifier off a legacy mangled name
+fn next_ident<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    let len: usize = rest[..digits].parse().ok()?;
+    let ident = rest.get(digits..digits + len)?;
+
+    *rest = &rest[digits + len..];
+    Some(ident)
+}
+
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17
+        && ident.starts_with('h')
+        && ident[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn write_ident(f: &mut fmt::Formatter<'_>, ident: &str) -> fmt::Result {
+    // The leading `_` is only there to keep an escape from starting the name
+    let mut rest = match ident.strip_prefix("_$") {
+        Some(_) => &ident[1..],
+        None => ident,
+    };
+
+    while !rest.is_empty() {
+        if let Some(escaped) = rest.strip_prefix('$') {
+            let Some(end) = escaped.find('$') else {
+                return f.write_str(rest);
+            };
+
+            let c = match &escaped[..end] {
+                "SP" => '@',
+                "BP" => '*',
+                "RF" => '&',
+                "LT" => '<',
+                "GT" => '>',
+                "LP" => '(',
+                "RP" => ')',
+                "C" => ',',
+                code => match code
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                {
+                    Some(c) => c,
+                    None => return f.write_str(rest),
+                },
+            };
+
+            write!(f, "{}", c)?;
+            rest = &escaped[end + 1..];
+        } else if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else {
+            let end = rest[1..].find(['$', '.']).map_or(rest.len(), |end| end + 1);
+
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+
+    Ok(())
+}
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN") else {
+            return f.write_str(self.0);
+        };
+
+        // Check it all first so nothing gets printed half demangled
+        let mut check = rest;
+        while !check.starts_with('E') {
+            if next_ident(&mut check).is_none() {
+                return f.write_str(self.0);
+            }
+        }
+
+        let mut first = true;
+        while let Some(ident) = next_ident(&mut rest) {
+            // The hash at the end only tells versions of a crate apart
+            if rest.starts_with('E') && is_hash(ident) {
+                break;
+            }
+
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+
+            write_ident(f, ident)?;
+        }
+
+        Ok(())
+    }
+}
+
+// One line of a trace, `lookup` is what to find the symbol with since a return
+// address can be just past the end of the function that made the call
+fn write_frame(f: &mut fmt::Formatter<'_>, depth: usize, addr: u64, lookup: u64) -> fmt::Result {
+    write!(f, "  #{:<2} {:#018x}", depth, addr)?;
+
+    match resolve(lookup) {
+        Some((name, offset)) => writeln!(f, " {}+{:#x}", Demangle(name), offset + (addr - lookup)),
+        None => writeln!(f),
+    }
+}
+
+// Walks the frame pointer chain (the kernel is built with
+// `force-frame-pointers`) starting at `rbp`, reading every frame through
+// `page_table` so a bad pointer can never fault
+pub fn walk(page_table: &PageTable, mut rbp: u64, mut f: impl FnMut(usize, u64)) {
+    for depth in 0..MAX_FRAMES {
+        if rbp == 0 || rbp & 0x7 != 0 {
+            break;
+        }
+
+        let mut frame = [0u8; 16];
+        if page_table
+            .read_virt(&mut PhysicalMemory, VirtAddr(rbp as usize), &mut frame)
+            .is_none()
+        {
+            break;
+        }
+
+        let next = u64::from_ne_bytes(frame[0..8].try_into().unwrap());
+        let ret = u64::from_ne_bytes(frame[8..16].try_into().unwrap());
+
+        if ret == 0 {
+            break;
+        }
+
+        f(depth, ret);
+
+        // Stacks grow down, so the chain must move towards higher addresses
+        if next <= rbp {
+            break;
+        }
+
+        rbp = next;
+    }
+}
+
+pub struct StackTrace<'a> {
+    pub page_table: &'a PageTable,
+    pub rip: u64,
+    pub rbp: u64,
+}
+
+impl<'a> fmt::Display for StackTrace<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Stack trace:")?;
+        write_frame(f, 0, self.rip, self.rip)?;
+
+        let mut result = Ok(());
+        walk(self.page_table, self.rbp, |depth, ret| {
+            if result.is_ok() {
+                result = write_frame(f, depth + 1, ret, ret - 1);
+            }
+        });
+
+        result
+    }
+}
+
+// Writes the stack of the caller. It goes through the active page table rather
+// than any lock, so it works from anywhere, panics included
+#[inline(never)]
+pub fn write_current(out: &mut dyn fmt::Write) -> fmt::Result {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp) };
+
+    let page_table =
+        unsafe { PageTable::from_table(PhysAddr(cpu::read_cr3() as usize & 0xffffffffff000)) };
+
+    write!(
+        out,
+        "{}",
+        StackTrace {
+            page_table: &page_table,
+            rip: write_current as fn(&mut dyn fmt::Write) -> fmt::Result as usize as u64,
+            rbp,
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::{resolve, Demangle};
+
+    #[test_case]
+    fn demangles_legacy_names() {
+        assert_eq!(
+            format!(
+                "{}",
+                Demangle("_ZN6kernel9backtrace4walk17h0123456789abcdefE")
+            ),
+            "kernel::backtrace::walk"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Demangle(
+                    "_ZN4core3ptr39drop_in_place$LT$kernel..task..Task$GT$17h0123456789abcdefE"
+                )
+            ),
+            "core::ptr::drop_in_place<kernel::task::Task>"
+        );
+        assert_eq!(format!("{}", Demangle("memcpy")), "memcpy");
+    }
+
+    #[test_case]
+    fn resolves_kernel_functions() {
+        let addr = resolve as fn(u64) -> Option<(&'static str, u64)> as usize as u64;
+        let (name, offset) = resolve(addr + 1).expect("no symbol for `resolve`");
+
+        assert_eq!(format!("{}", Demangle(name)), "kernel::backtrace::resolve");
+        assert_eq!(offset, 1);
+    }
+}
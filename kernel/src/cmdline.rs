@@ -0,0 +1,203 @@
+use stivale_boot::v2::StivaleStruct;
+
+use crate::sync::Once;
+
+// Anything past this is ignored
+const MAX_COMMAND_LINE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedPolicy {
+    // Idle cores steal work from busy ones, and every core evens out its
+    // queue against the busiest one now and then
+    Balance,
+    // Tasks stay on whatever core they were queued on
+    Local,
+}
+
+impl SchedPolicy {
+    fn parse(value: &str) -> Option<SchedPolicy> {
+        match value {
+            "balance" => Some(SchedPolicy::Balance),
+            "local" => Some(SchedPolicy::Local),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    Halt,
+    Reboot,
+    // Halts instead when not running under QEMU with its exit device
+    ExitQemu,
+}
+
+impl PanicAction {
+    fn parse(value: &str) -> Option<PanicAction> {
+        match value {
+            "halt" => Some(PanicAction::Halt),
+            "reboot" => Some(PanicAction::Reboot),
+            "exit-qemu" => Some(PanicAction::ExitQemu),
+            _ => None,
+        }
+    }
+
+    // What the build picked with the panic_* features
+    const fn build_default() -> PanicAction {
+        if cfg!(any(test, feature = "panic_exit_qemu")) {
+            PanicAction::ExitQemu
+        } else if cfg!(feature = "panic_reboot") {
+            PanicAction::Reboot
+        } else {
+            PanicAction::Halt
+        }
+    }
+}
+
+// The kernel command line, as `key=value` options separated by spaces
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    // `log=`, level directives in the form `logging` takes them
+    pub log: &'static str,
+    // `init=`, the module string of the first user program
+    pub init: &'static str,
+    // `sched=balance|local`
+    pub sched: SchedPolicy,
+    // `panic=halt|reboot|exit-qemu`
+    pub panic: PanicAction,
+    // `test` or `test=<filter>`, set when automation runs the kernel. Panics
+    // exit QEMU unless `panic=` says otherwise, and test builds only run the
+    // tests with `filter` in their name
+    pub test: Option<&'static str>,
+}
+
+static DEFAULT: Config = Config {
+    log: "",
+    init: "__INIT__",
+    sched: SchedPolicy::Balance,
+    panic: PanicAction::build_default(),
+    test: None,
+};
+
+static CONFIG: Once<Config> = Once::new();
+
+// Whatever the stivale2 command line tag holds, if it is there and valid
+fn command_line(boot_info: &StivaleStruct) -> Option<&'static str> {
+    let ptr = boot_info.command_line()?.command_line as *const u8;
+    if ptr.is_null() {
+        return None;
+    }
+
+    let len = (0..MAX_COMMAND_LINE)
+        .find(|&offset| unsafe { *ptr.add(offset) } == 0)
+        .unwrap_or(MAX_COMMAND_LINE);
+    core::str::from_utf8(unsafe { core::slice::from_raw_parts(ptr, len) }).ok()
+}
+
+// Takes one option into `config`, None if it is not one we know
+fn apply(
+    config: &mut Config,
+    panic: &mut Option<PanicAction>,
+    key: &str,
+    value: Option<&'static str>,
+) -> Option<()> {
+    match key {
+        "log" => config.log = value?,
+        "init" => config.init = value.filter(|value| !value.is_empty())?,
+        "sched" => config.sched = SchedPolicy::parse(value?)?,
+        "panic" => *panic = Some(PanicAction::parse(value?)?),
+        "test" => config.test = Some(value.unwrap_or("")),
+        _ => return None,
+    }
+
+    Some(())
+}
+
+fn parse(line: &'static str) -> Config {
+    let mut config = DEFAULT;
+    let mut panic = None;
+
+    for option in line.split_whitespace() {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        };
+
+        if apply(&mut config, &mut panic, key, value).is_none() {
+            log::warn!("Ignoring command line option `{}`", option);
+        }
+    }
+
+    config.panic = match (panic, config.test) {
+        (Some(action), _) => action,
+        (None, Some(_)) => PanicAction::ExitQemu,
+        (None, None) => config.panic,
+    };
+
+    config
+}
+
+// Parses the command line from the bootloader, must run before anything else
+// asks for the config. The logger must be set up already, anything it logs
+// here goes out at the default levels
+pub fn init(boot_info: &StivaleStruct) {
+    let line = command_line(boot_info).unwrap_or("");
+    let config = CONFIG.call_once(|| parse(line));
+
+    log::info!("Command line `{}`: {:?}", line, config);
+}
+
+// The defaults until `init` ran, panics and logging may ask before then
+pub fn get() -> &'static Config {
+    CONFIG.get().unwrap_or(&DEFAULT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, PanicAction, SchedPolicy, DEFAULT};
+
+    #[test_case]
+    fn empty_line_gives_the_defaults() {
+        let config = parse("");
+
+        assert_eq!(config.log, DEFAULT.log);
+        assert_eq!(config.init, "__INIT__");
+        assert_eq!(config.sched, SchedPolicy::Balance);
+        assert_eq!(config.panic, DEFAULT.panic);
+        assert_eq!(config.test, None);
+    }
+
+    #[test_case]
+    fn options_are_taken() {
+        let config = parse("log=warn,kernel::sched=debug  init=shell sched=local");
+
+        assert_eq!(config.log, "warn,kernel::sched=debug");
+        assert_eq!(config.init, "shell");
+        assert_eq!(config.sched, SchedPolicy::Local);
+    }
+
+    #[test_case]
+    fn test_with_and_without_a_filter() {
+        assert_eq!(parse("test").test, Some(""));
+        assert_eq!(parse("test=").test, Some(""));
+        assert_eq!(parse("test=rangeset").test, Some("rangeset"));
+    }
+
+    #[test_case]
+    fn test_mode_exits_qemu_unless_panic_is_given() {
+        assert_eq!(parse("test").panic, PanicAction::ExitQemu);
+        assert_eq!(parse("test panic=halt").panic, PanicAction::Halt);
+        assert_eq!(parse("panic=reboot test").panic, PanicAction::Reboot);
+        assert_eq!(parse("panic=exit-qemu").panic, PanicAction::ExitQemu);
+    }
+
+    #[test_case]
+    fn bad_options_are_ignored() {
+        let config = parse("init= init sched=fair panic=explode verbose=1 quiet");
+
+        assert_eq!(config.init, "__INIT__");
+        assert_eq!(config.sched, SchedPolicy::Balance);
+        assert_eq!(config.panic, DEFAULT.panic);
+        assert_eq!(config.test, None);
+    }
+}
@@ -1,22 +1,67 @@
-use core::{alloc::Layout, sync::atomic::AtomicUsize, sync::atomic::Ordering};
+use core::{
+    alloc::Layout,
+    sync::atomic::Ordering,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize},
+};
 
+use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
 
-use crate::{interrupts::Interrupts, mm::PhysMem, paging::PageTable, sync::LockCell, task::Task};
+use crate::{
+    interrupts::Interrupts,
+    mm::PhysMem,
+    sync::{IrqLockCell, LockCell},
+    task::Task,
+};
+
+pub const MAX_CORES: usize = 64;
 
 static CORES_ONLINE: AtomicUsize = AtomicUsize::new(0);
 
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CORE: AtomicPtr<CoreLocals> = AtomicPtr::new(core::ptr::null_mut());
+
+// Every core that went through `init`, indexed by core ID
+static CORES: [AtomicPtr<CoreLocals>; MAX_CORES] = [NO_CORE; MAX_CORES];
+
 #[repr(C)]
 pub struct CoreLocals {
     address: usize,
 
     pub id: usize,
+    pub apic_id: u32,
+
+    // Root of the page table currently loaded in CR3
+    pub active_page_table: AtomicUsize,
 
-    pub kernel_page_table: LockCell<Option<PageTable>>,
     pub interrupt_state: LockCell<Option<Interrupts>>,
-    pub tasks: LockCell<Vec<&'static mut Task>>,
-    pub current_task_id: LockCell<usize>,
+    // Boxed so a task moves between queues and wait lists without copying
+    #[allow(clippy::vec_box)]
+    pub tasks: IrqLockCell<Vec<Box<Task>>>,
+    pub current_task_id: IrqLockCell<usize>,
+
+    // Top of this core's own stack, which the scheduler switches to before it
+    // lets go of a task
+    pub stack: AtomicUsize,
+    // This core's TSS, its rsp0 follows the running task
+    pub tss: AtomicPtr<u8>,
+    // This core's IDT, registering a vector writes its gate into all of them
+    pub idt: AtomicPtr<u128>,
+    // ID of the task whose kernel stack this core may still be on, 0 for none.
+    // No other core enters that task until this is cleared
+    pub on_stack_of: AtomicUsize,
+    // Set by the tick when the task running here should make room for the
+    // next one in our queue, done on the way back to user mode
+    pub preempt: AtomicBool,
+
+    // Scheduler statistics, see `sched::utilization`
+    pub idle: AtomicBool,
+    pub online_tsc: u64,
+    pub idle_cycles: AtomicU64,
+    pub switches: AtomicU64,
+    pub ticks: AtomicU64,
+    pub steals: AtomicU64,
 }
 
 trait CoreGuard: Sync + Sized {}
@@ -40,6 +85,23 @@ pub fn get_core_locals() -> &'static CoreLocals {
     }
 }
 
+// Like `core!()`, but None on a core that has not been through `init` yet
+pub fn try_get_core_locals() -> Option<&'static CoreLocals> {
+    if unsafe { crate::cpu::rdmsr(crate::cpu::IA32_GS_BASE) } == 0 {
+        return None;
+    }
+
+    Some(get_core_locals())
+}
+
+pub fn get_core(id: usize) -> Option<&'static CoreLocals> {
+    unsafe { CORES.get(id)?.load(Ordering::SeqCst).as_ref() }
+}
+
+pub fn cores() -> impl Iterator<Item = &'static CoreLocals> {
+    (0..MAX_CORES).filter_map(get_core)
+}
+
 pub fn init(phys_mem: &mut dyn PhysMem) {
     let core_locals_ptr = phys_mem
         .alloc_phys_zeroed(
@@ -55,14 +117,30 @@ pub fn init(phys_mem: &mut dyn PhysMem) {
     let core_locals = CoreLocals {
         address: core_locals_ptr,
         id: CORES_ONLINE.fetch_add(1, Ordering::SeqCst),
-        kernel_page_table: LockCell::new(None),
+        apic_id: crate::cpu::cpuid(1, 0).1 >> 24,
+        active_page_table: AtomicUsize::new(crate::cpu::read_cr3() as usize & 0xffffffffff000),
         interrupt_state: LockCell::new(None),
-        tasks: LockCell::new(vec![]),
-        current_task_id: LockCell::new(0),
+        tasks: IrqLockCell::new(vec![]),
+        current_task_id: IrqLockCell::new(0),
+        stack: AtomicUsize::new(0),
+        tss: AtomicPtr::new(core::ptr::null_mut()),
+        idt: AtomicPtr::new(core::ptr::null_mut()),
+        on_stack_of: AtomicUsize::new(0),
+        preempt: AtomicBool::new(false),
+        idle: AtomicBool::new(false),
+        online_tsc: unsafe { crate::cpu::rdtsc() },
+        idle_cycles: AtomicU64::new(0),
+        switches: AtomicU64::new(0),
+        ticks: AtomicU64::new(0),
+        steals: AtomicU64::new(0),
     };
 
+    assert!(core_locals.id < MAX_CORES, "Too many cores");
+
     unsafe {
         core::ptr::write(core_locals_ptr as *mut CoreLocals, core_locals);
+        CORES[(*(core_locals_ptr as *const CoreLocals)).id]
+            .store(core_locals_ptr as *mut CoreLocals, Ordering::SeqCst);
         crate::cpu::set_kernel_gs_base(core_locals_ptr as u64);
         core::arch::asm!("swapgs");
     }
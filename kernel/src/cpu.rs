@@ -1,5 +1,11 @@
 #![allow(dead_code)]
 
+// MSR for the local APIC base and enable bits
+pub const IA32_APIC_BASE: u32 = 0x1b;
+
+// MSR for the FS base, user mode uses it for thread local storage
+pub const IA32_FS_BASE: u32 = 0xc0000100;
+
 // MSR for active GS base
 pub const IA32_GS_BASE: u32 = 0xc0000101;
 
@@ -21,6 +27,18 @@ pub unsafe fn rdmsr(msr: u32) -> u64 {
     ((high as u64) << 32) | low as u64
 }
 
+#[inline]
+pub unsafe fn inb(port: u16) -> u8 {
+    let val: u8;
+    core::arch::asm!("in al, dx", in("dx") port, out("al") val);
+    val
+}
+
+#[inline]
+pub unsafe fn outb(port: u16, val: u8) {
+    core::arch::asm!("out dx, al", in("dx") port, in("al") val);
+}
+
 #[inline]
 pub unsafe fn set_gs_base(base: u64) {
     wrmsr(IA32_GS_BASE, base);
@@ -41,25 +59,74 @@ pub unsafe fn set_cr3(new_cr3: usize) {
     core::arch::asm!("mov cr3, {}", in(reg) new_cr3);
 }
 
+// Returns (eax, ebx, ecx, edx) for the given leaf and subleaf
 #[inline]
-pub unsafe fn rdtsc() -> u64 {
-    core::arch::x86_64::_rdtsc()
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let res = unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) };
+    (res.eax, res.ebx, res.ecx, res.edx)
+}
+
+#[inline]
+pub fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe { core::arch::asm!("mov {}, cr0", out(reg) cr0) };
+    cr0
+}
+
+#[inline]
+pub fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe { core::arch::asm!("mov {}, cr2", out(reg) cr2) };
+    cr2
+}
+
+#[inline]
+pub fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe { core::arch::asm!("mov {}, cr3", out(reg) cr3) };
+    cr3
 }
 
 #[inline]
-pub unsafe fn to_usermode(at: usize, sp: usize) -> ! {
-    core::arch::asm!(r#"
-        mov bx, 0x20 | 3
-        mov ds, bx
-        mov es, bx
-        
-        swapgs
+pub fn read_cr4() -> u64 {
+    let cr4: u64;
+    unsafe { core::arch::asm!("mov {}, cr4", out(reg) cr4) };
+    cr4
+}
+
+#[inline]
+pub fn halt_forever() -> ! {
+    loop {
+        unsafe { core::arch::asm!("sti", "hlt") };
+    }
+}
+
+// Parks the core for good. An NMI still gets it out of `hlt`, so loop
+#[inline]
+pub fn stop() -> ! {
+    loop {
+        unsafe { core::arch::asm!("cli", "hlt") };
+    }
+}
+
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe { core::arch::asm!("pushfq", "pop {}", out(reg) rflags) };
+    rflags & (1 << 9) != 0
+}
 
-        push 0x20 | 3
-        push rcx
-        pushfq
-        push 0x18 | 3
-        push rax
-        iretq
-    "#, in("rax") at, in("rcx") sp, options(noreturn));
+#[inline]
+pub unsafe fn disable_interrupts() {
+    core::arch::asm!("cli");
+}
+
+#[inline]
+pub unsafe fn enable_interrupts() {
+    core::arch::asm!("sti");
+}
+
+#[inline]
+pub unsafe fn rdtsc() -> u64 {
+    core::arch::x86_64::_rdtsc()
 }
@@ -0,0 +1,258 @@
+use core::fmt;
+
+use crate::{
+    backtrace::StackTrace,
+    cpu, gdb,
+    interrupts::{InterruptFrame, Registers},
+    mm::{PhysAddr, PhysicalMemory, VirtAddr},
+    paging::{self, PageTable},
+    panic,
+};
+
+pub const EXCEPTION_NAMES: [(&str, &str); 32] = [
+    ("#DE", "Divide error"),
+    ("#DB", "Debug"),
+    ("NMI", "Non-maskable interrupt"),
+    ("#BP", "Breakpoint"),
+    ("#OF", "Overflow"),
+    ("#BR", "Bound range exceeded"),
+    ("#UD", "Invalid opcode"),
+    ("#NM", "Device not available"),
+    ("#DF", "Double fault"),
+    ("#CSO", "Coprocessor segment overrun"),
+    ("#TS", "Invalid TSS"),
+    ("#NP", "Segment not present"),
+    ("#SS", "Stack-segment fault"),
+    ("#GP", "General protection fault"),
+    ("#PF", "Page fault"),
+    ("#15", "Reserved"),
+    ("#MF", "x87 floating-point exception"),
+    ("#AC", "Alignment check"),
+    ("#MC", "Machine check"),
+    ("#XM", "SIMD floating-point exception"),
+    ("#VE", "Virtualization exception"),
+    ("#CP", "Control protection exception"),
+    ("#22", "Reserved"),
+    ("#23", "Reserved"),
+    ("#24", "Reserved"),
+    ("#25", "Reserved"),
+    ("#26", "Reserved"),
+    ("#27", "Reserved"),
+    ("#HV", "Hypervisor injection exception"),
+    ("#VC", "VMM communication exception"),
+    ("#SX", "Security exception"),
+    ("#31", "Reserved"),
+];
+
+// Vectors that are fatal no matter which ring they came from
+const fn always_fatal(vector: u8) -> bool {
+    matches!(vector, 2 | 8 | 18)
+}
+
+pub extern "C" fn exception<const VECTOR: u8>(frame: &mut InterruptFrame, regs: &mut Registers) {
+    handle_exception(VECTOR, frame, None, regs)
+}
+
+pub extern "C" fn exception_errorcode<const VECTOR: u8>(
+    frame: &mut InterruptFrame,
+    error_code: u64,
+    regs: &mut Registers,
+) {
+    handle_exception(VECTOR, frame, Some(error_code), regs)
+}
+
+fn handle_exception(
+    vector: u8,
+    frame: &mut InterruptFrame,
+    error_code: Option<u64>,
+    regs: &mut Registers,
+) {
+    // Another core panicked and wants everyone else to stop
+    if vector == 2 && panic::panicking() {
+        cpu::stop();
+    }
+
+    let user = frame.cs & 0x3 == 0x3;
+
+    if user {
+        unsafe { core::arch::asm!("swapgs") };
+    }
+
+    // Breakpoints and single steps go to the debugger if one is attached
+    if matches!(vector, 1 | 3) && gdb::handle_trap(vector, frame, regs) {
+        if user {
+            unsafe { core::arch::asm!("swapgs") };
+        }
+        return;
+    }
+
+    // Capture the control registers before anything can change them, CR3 is
+    // still the page table of whatever faulted
+    let report = ExceptionReport {
+        vector,
+        user,
+        frame,
+        error_code,
+        regs,
+        cr0: cpu::read_cr0(),
+        cr2: cpu::read_cr2(),
+        cr3: cpu::read_cr3(),
+        cr4: cpu::read_cr4(),
+    };
+
+    // A kernel fault may have happened with any lock held, so report it
+    // without touching any of them
+    if !user || always_fatal(vector) {
+        panic!("{}", report);
+    }
+
+    log::error!("{}", report);
+
+    unsafe {
+        paging::load_kernel();
+    }
+
+    crate::task::kill_current()
+}
+
+pub struct ExceptionReport<'a> {
+    pub vector: u8,
+    pub user: bool,
+    pub frame: &'a InterruptFrame,
+    pub error_code: Option<u64>,
+    pub regs: &'a Registers,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl<'a> fmt::Display for ExceptionReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mnemonic, name) = EXCEPTION_NAMES[self.vector as usize];
+
+        writeln!(
+            f,
+            "{} ({}) in {} mode at {:#x}",
+            name,
+            mnemonic,
+            if self.user { "user" } else { "kernel" },
+            self.frame.rip
+        )?;
+
+        if let Some(error_code) = self.error_code {
+            write!(f, "  Error code: {:#x}", error_code)?;
+
+            match self.vector {
+                14 => writeln!(f, " ({})", PageFaultError(error_code))?,
+                10 | 11 | 12 | 13 | 17 | 21 if error_code != 0 => {
+                    writeln!(f, " ({})", SelectorError(error_code))?
+                }
+                _ => writeln!(f)?,
+            }
+        }
+
+        writeln!(
+            f,
+            "  CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )?;
+
+        // Read everything through the faulting address space, it may not be
+        // the one that is active once we get to print this
+        let page_table = unsafe { PageTable::from_table(PhysAddr(self.cr3 as usize)) };
+
+        write!(f, "  Instruction bytes:")?;
+        for offset in 0..16 {
+            let mut byte = [0u8];
+            match page_table.read_virt(
+                &mut PhysicalMemory,
+                VirtAddr(self.frame.rip as usize + offset),
+                &mut byte,
+            ) {
+                Some(()) => write!(f, " {:02x}", byte[0])?,
+                None => {
+                    write!(f, " <unmapped>")?;
+                    break;
+                }
+            }
+        }
+        writeln!(f)?;
+
+        writeln!(f, "{:#x?}", self.frame)?;
+        writeln!(f, "{:#x?}", self.regs)?;
+
+        write!(
+            f,
+            "{}",
+            StackTrace {
+                page_table: &page_table,
+                rip: self.frame.rip,
+                rbp: self.regs.rbp,
+            }
+        )
+    }
+}
+
+// Error code pushed by #TS, #NP, #SS, #GP, #AC and #CP for a faulting selector
+pub struct SelectorError(pub u64);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = match (self.0 >> 1) & 0x3 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        };
+
+        write!(
+            f,
+            "external: {}, table: {}, index: {:#x}",
+            self.0 & 1 != 0,
+            table,
+            (self.0 >> 3) & 0x1fff
+        )
+    }
+}
+
+pub struct PageFaultError(pub u64);
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} from {} mode",
+            if self.0 & (1 << 0) != 0 {
+                "protection violation"
+            } else {
+                "non-present page"
+            },
+            if self.0 & (1 << 4) != 0 {
+                "on instruction fetch"
+            } else if self.0 & (1 << 1) != 0 {
+                "on write"
+            } else {
+                "on read"
+            },
+            if self.0 & (1 << 2) != 0 {
+                "user"
+            } else {
+                "kernel"
+            },
+        )?;
+
+        if self.0 & (1 << 3) != 0 {
+            write!(f, ", reserved bit set")?;
+        }
+
+        if self.0 & (1 << 5) != 0 {
+            write!(f, ", protection key")?;
+        }
+
+        if self.0 & (1 << 15) != 0 {
+            write!(f, ", SGX")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    interrupts::{InterruptFrame, Registers},
+    mm::{self, PhysAddr, PhysMem},
+    sched,
+    sync::IrqLockCell,
+    syscall::ERROR,
+    task::{self, Task},
+    time::{self, TimerAction},
+};
+
+// What `futex_wait` returns when the timeout ran out before a wake
+pub const TIMED_OUT: u64 = 1;
+
+const BUCKETS: usize = 64;
+
+// Futexes are keyed on the physical address of the word, so tasks sharing
+// memory can use them on whatever address they have it mapped at
+struct Waiter {
+    key: PhysAddr,
+    id: u64,
+    task: Box<Task>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BUCKET: IrqLockCell<Vec<Waiter>> = IrqLockCell::new(Vec::new());
+
+static WAITERS: [IrqLockCell<Vec<Waiter>>; BUCKETS] = [EMPTY_BUCKET; BUCKETS];
+
+// Tells waiters apart for timeouts, the low bits are the bucket
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn bucket(key: PhysAddr) -> usize {
+    (key.0 / 4) % BUCKETS
+}
+
+// Fires for every wait with a timeout, if the waiter was woken already it is
+// just not found
+fn timeout(id: *mut ()) {
+    let id = id as u64;
+    let mut waiters = WAITERS[id as usize % BUCKETS].lock();
+
+    if let Some(index) = waiters.iter().position(|waiter| waiter.id == id) {
+        // Not swap_remove, the rest of the bucket has to stay oldest first
+        let mut waiter = waiters.remove(index);
+        waiter.task.set_return(TIMED_OUT, 0);
+        sched::enqueue(waiter.task);
+    }
+}
+
+// futex_wait(addr, expected, timeout_ns), blocks while the u32 at `addr` holds
+// `expected`. A timeout of 0 waits for as long as it takes. Returns 0 when
+// woken, `TIMED_OUT`, or an error straight away if the value did not match
+pub fn sys_futex_wait(frame: &InterruptFrame, regs: &mut Registers) {
+    let addr = regs.rdi;
+    let expected = regs.rsi as u32;
+    let timeout_ns = regs.rdx;
+
+    let key = match task::with_current(|task| task.user_phys(addr)) {
+        Some(key) if addr % 4 == 0 => key,
+        _ => {
+            regs.rax = ERROR;
+            return;
+        }
+    };
+
+    let index = bucket(key);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) * BUCKETS as u64 + index as u64;
+    let deadline = time::now().saturating_add(timeout_ns);
+
+    regs.rax = 0;
+    sched::block_current(frame, regs, &mut |mut task| {
+        let mut waiters = WAITERS[index].lock();
+
+        // Checked under the bucket lock, a wake after the store we would miss
+        // has to wait for us to be queued. The word is read through its frame,
+        // walking the page table here could deadlock against a shootdown
+        let value = unsafe {
+            mm::PhysicalMemory
+                .translate(key, 4)
+                .map(|word| core::ptr::read_volatile(word as *const u32))
+        };
+        if value != Some(expected) {
+            drop(waiters);
+            task.set_return(ERROR, 0);
+            sched::enqueue(task);
+            return;
+        }
+
+        waiters.push(Waiter { key, id, task });
+        drop(waiters);
+
+        if timeout_ns != 0 {
+            time::add_timer(deadline, TimerAction::Call(timeout, id as *mut ()));
+        }
+    })
+}
+
+fn wake(addr: u64, count: u64) -> Option<u64> {
+    if addr % 4 != 0 {
+        return None;
+    }
+
+    let key = task::with_current(|task| task.user_phys(addr))?;
+    let mut waiters = WAITERS[bucket(key)].lock();
+
+    // Oldest first
+    let mut woken = 0;
+    let mut index = 0;
+    while woken < count && index < waiters.len() {
+        if waiters[index].key == key {
+            sched::enqueue(waiters.remove(index).task);
+            woken += 1;
+        } else {
+            index += 1;
+        }
+    }
+
+    Some(woken)
+}
+
+// futex_wake(addr, count), wakes up to `count` tasks waiting on `addr` and
+// returns how many there were
+pub fn sys_futex_wake(regs: &mut Registers) {
+    regs.rax = wake(regs.rdi, regs.rsi).unwrap_or(ERROR);
+}
@@ -0,0 +1,804 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    core_locals, cpu,
+    interrupts::{Interrupt, InterruptFrame, Registers},
+    irq,
+    mm::{PhysAddr, PhysicalMemory, VirtAddr},
+    paging::{self, PageTable},
+    sched,
+    serial::COM2,
+    sync::{LockCell, LockCellGuard},
+    task::Context,
+    tlb,
+};
+
+const COM2_IRQ: u8 = 3;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const MAX_PACKET: usize = 4096;
+const MAX_BREAKPOINTS: usize = 64;
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const INT3: u8 = 0xcc;
+
+// Thread ids above this stand for a core running kernel code, the ones below
+// are task ids
+const KERNEL_THREAD: usize = 0x1_0000_0000;
+
+// General purpose registers, rip, then eflags and the segments, in the order
+// GDB expects them for x86-64
+const REGISTERS: usize = 24;
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+// Set while GDB thinks we are running, it expects a stop reply on the next stop
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    page_table: usize,
+    addr: usize,
+    original: u8,
+}
+
+impl Breakpoint {
+    // The kernel half is the same in every page table
+    fn matches(&self, addr: usize, page_table: usize) -> bool {
+        self.addr == addr && (self.page_table == page_table || addr >= paging::USER_END)
+    }
+}
+
+// Everything lives here rather than on the heap or stack, the stub may be
+// entered with the allocator locked
+struct Stub {
+    packet: [u8; MAX_PACKET],
+    reply: Packet,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+static STUB: LockCell<Stub> = LockCell::new(Stub {
+    packet: [0; MAX_PACKET],
+    reply: Packet {
+        data: [0; MAX_PACKET],
+        len: 0,
+    },
+    breakpoints: [None; MAX_BREAKPOINTS],
+});
+
+struct Packet {
+    data: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Packet {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_PACKET - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let _ = write!(self, "{:02x}", byte);
+        }
+    }
+
+    fn send(&self) {
+        let data = &self.data[..self.len];
+        let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+
+        COM2.write(b"$");
+        COM2.write(data);
+        COM2.write(b"#");
+
+        let mut hex = [0; 2];
+        hex[0] = HEX_DIGITS[(checksum >> 4) as usize];
+        hex[1] = HEX_DIGITS[(checksum & 0xf) as usize];
+        COM2.write(&hex);
+    }
+}
+
+impl Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+// Formats straight into a packet as hex, for replies GDB wants encoded
+struct HexWriter<'a>(&'a mut Packet);
+
+impl<'a> Write for HexWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push_hex(s.as_bytes());
+        Ok(())
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(text: &[u8]) -> Option<usize> {
+    if text.is_empty() || text.len() > 16 {
+        return None;
+    }
+
+    text.iter().try_fold(0usize, |val, &digit| {
+        Some(val << 4 | hex_value(digit)? as usize)
+    })
+}
+
+// Decodes hex pairs into `out`, which must be exactly big enough
+fn decode_hex(text: &[u8], out: &mut [u8]) -> Option<()> {
+    if text.len() != out.len() * 2 {
+        return None;
+    }
+
+    for (byte, pair) in out.iter_mut().zip(text.chunks(2)) {
+        *byte = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+
+    Some(())
+}
+
+fn getc() -> u8 {
+    loop {
+        if let Some(byte) = COM2.poll() {
+            return byte;
+        }
+
+        tlb::service_pending();
+        core::hint::spin_loop();
+    }
+}
+
+// Waits for a packet with a good checksum and acknowledges it, returning its
+// length. A NAK for our last reply sends it again
+fn read_packet(packet: &mut [u8; MAX_PACKET], reply: &Packet) -> usize {
+    loop {
+        match getc() {
+            b'$' => {}
+            b'-' => {
+                reply.send();
+                continue;
+            }
+            _ => continue,
+        }
+
+        let mut len = 0;
+        let mut checksum = 0u8;
+        let mut overflow = false;
+
+        loop {
+            let byte = getc();
+            if byte == b'#' {
+                break;
+            }
+
+            if len < MAX_PACKET {
+                packet[len] = byte;
+                len += 1;
+            } else {
+                overflow = true;
+            }
+            checksum = checksum.wrapping_add(byte);
+        }
+
+        let expected = hex_value(getc()).zip(hex_value(getc()));
+        if overflow || expected.map(|(high, low)| high << 4 | low) != Some(checksum) {
+            COM2.write(b"-");
+            continue;
+        }
+
+        COM2.write(b"+");
+        return len;
+    }
+}
+
+// What a stopped thread looks like to GDB
+struct Thread {
+    page_table: usize,
+    regs: [u64; REGISTERS],
+}
+
+fn registers(frame: &InterruptFrame, regs: &Registers) -> [u64; REGISTERS] {
+    [
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rbp,
+        frame.rsp,
+        regs.r8,
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12,
+        regs.r13,
+        regs.r14,
+        regs.r15,
+        frame.rip,
+        frame.rflags,
+        frame.cs,
+        frame.ss,
+        // ds, es, fs and gs, nobody uses them for anything
+        0,
+        0,
+        0,
+        0,
+    ]
+}
+
+fn set_registers(frame: &mut InterruptFrame, regs: &mut Registers, values: &[u64; REGISTERS]) {
+    regs.rax = values[0];
+    regs.rbx = values[1];
+    regs.rcx = values[2];
+    regs.rdx = values[3];
+    regs.rsi = values[4];
+    regs.rdi = values[5];
+    regs.rbp = values[6];
+    frame.rsp = values[7];
+    regs.r8 = values[8];
+    regs.r9 = values[9];
+    regs.r10 = values[10];
+    regs.r11 = values[11];
+    regs.r12 = values[12];
+    regs.r13 = values[13];
+    regs.r14 = values[14];
+    regs.r15 = values[15];
+    frame.rip = values[16];
+
+    // Only the flags a debugger has any business changing
+    const WRITABLE_FLAGS: u64 = 0xcd5 | RFLAGS_TF;
+    frame.rflags = (frame.rflags & !WRITABLE_FLAGS) | (values[17] & WRITABLE_FLAGS);
+}
+
+// Size GDB uses for each register in `g` packets
+fn register_size(index: usize) -> usize {
+    if index <= 16 {
+        8
+    } else {
+        4
+    }
+}
+
+// Calls `f` for every task on a run queue, with its saved context. A queue
+// that is locked is skipped, its core may be stopped in here holding the lock
+fn for_each_task(mut f: impl FnMut(usize, usize, &Context)) {
+    for core in core_locals::cores() {
+        let tasks = match core.tasks.try_lock() {
+            Some(tasks) => tasks,
+            None => continue,
+        };
+
+        for task in tasks.iter() {
+            f(task.id(), task.page_table().0, task.context());
+        }
+    }
+}
+
+fn find_thread(id: usize) -> Option<Thread> {
+    let mut found = None;
+
+    for_each_task(|task, page_table, context| {
+        if task == id {
+            let frame = InterruptFrame {
+                rip: context.rip as u64,
+                cs: context.cs,
+                rflags: context.rflags,
+                rsp: context.rsp as u64,
+                ss: context.ss,
+            };
+
+            found = Some(Thread {
+                page_table,
+                regs: registers(&frame, &context.regs),
+            });
+        }
+    });
+
+    found
+}
+
+struct Session<'a> {
+    reply: &'a mut Packet,
+    breakpoints: &'a mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    frame: &'a mut InterruptFrame,
+    regs: &'a mut Registers,
+    signal: u8,
+
+    // The thread that stopped and the one `Hg` picked for registers and memory
+    current: usize,
+    selected: Option<Thread>,
+    page_table: usize,
+}
+
+impl<'a> Session<'a> {
+    fn page_table(&self) -> PageTable {
+        let table = match &self.selected {
+            Some(thread) => thread.page_table,
+            None => self.page_table,
+        };
+
+        unsafe { PageTable::from_table(PhysAddr(table)) }
+    }
+
+    fn stop_reply(&mut self) {
+        let (signal, current) = (self.signal, self.current);
+        let _ = write!(self.reply, "T{:02x}thread:{:x};", signal, current);
+    }
+
+    fn read_registers(&mut self) {
+        let regs = match &self.selected {
+            Some(thread) => thread.regs,
+            None => registers(self.frame, self.regs),
+        };
+
+        for (index, reg) in regs.iter().enumerate() {
+            let bytes = reg.to_le_bytes();
+            self.reply.push_hex(&bytes[..register_size(index)]);
+        }
+    }
+
+    fn write_register(&mut self, index: usize, value: &[u8]) -> Option<()> {
+        // Tasks that are not running only have their saved state, leave it be
+        if self.selected.is_some() || index >= REGISTERS {
+            return None;
+        }
+
+        let mut bytes = [0u8; 8];
+        decode_hex(value, &mut bytes[..register_size(index)])?;
+
+        let mut regs = registers(self.frame, self.regs);
+        regs[index] = u64::from_le_bytes(bytes);
+        set_registers(self.frame, self.regs, &regs);
+
+        Some(())
+    }
+
+    fn write_registers(&mut self, values: &[u8]) -> Option<()> {
+        let mut offset = 0;
+        for index in 0..REGISTERS {
+            let len = register_size(index) * 2;
+            let value = values.get(offset..offset + len)?;
+
+            self.write_register(index, value)?;
+            offset += len;
+        }
+
+        Some(())
+    }
+
+    fn read_memory(&mut self, addr: usize, len: usize) -> Option<()> {
+        let page_table = self.page_table();
+
+        for offset in 0..len.min((MAX_PACKET - 1) / 2) {
+            let mut byte = [0];
+            page_table.read_virt(
+                &mut PhysicalMemory,
+                VirtAddr(addr.checked_add(offset)?),
+                &mut byte,
+            )?;
+
+            // Our breakpoints are not part of the program
+            let original = self
+                .breakpoints
+                .iter()
+                .flatten()
+                .find(|bp| bp.matches(addr + offset, page_table.table().0));
+            self.reply
+                .push_hex(&[original.map_or(byte[0], |bp| bp.original)]);
+        }
+
+        Some(())
+    }
+
+    fn write_memory(&mut self, addr: usize, data: &[u8]) -> Option<()> {
+        let page_table = self.page_table();
+
+        for (offset, pair) in data.chunks(2).enumerate() {
+            let mut byte = [0];
+            decode_hex(pair, &mut byte)?;
+            page_table.write_virt(
+                &mut PhysicalMemory,
+                VirtAddr(addr.checked_add(offset)?),
+                &byte,
+            )?;
+        }
+
+        Some(())
+    }
+
+    fn insert_breakpoint(&mut self, addr: usize) -> Option<()> {
+        let page_table = self.page_table();
+        let table = page_table.table().0;
+
+        if self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|bp| bp.matches(addr, table))
+        {
+            return Some(());
+        }
+
+        let slot = self.breakpoints.iter_mut().find(|bp| bp.is_none())?;
+
+        let mut original = [0];
+        page_table.read_virt(&mut PhysicalMemory, VirtAddr(addr), &mut original)?;
+        page_table.write_virt(&mut PhysicalMemory, VirtAddr(addr), &[INT3])?;
+
+        *slot = Some(Breakpoint {
+            page_table: table,
+            addr,
+            original: original[0],
+        });
+
+        Some(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: usize) -> Option<()> {
+        let page_table = self.page_table();
+        let table = page_table.table().0;
+
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|bp| matches!(bp, Some(bp) if bp.matches(addr, table)))?;
+
+        let original = slot.take()?.original;
+        page_table.write_virt(&mut PhysicalMemory, VirtAddr(addr), &[original])
+    }
+
+    fn thread_list(&mut self) {
+        let current = self.current;
+        let reply = &mut self.reply;
+
+        let _ = write!(reply, "m{:x}", current);
+        for_each_task(|id, _, _| {
+            if id != current {
+                let _ = write!(reply, ",{:x}", id);
+            }
+        });
+    }
+
+    fn thread_alive(&self, id: usize) -> bool {
+        id == self.current || find_thread(id).is_some()
+    }
+
+    fn thread_info(&mut self, id: usize) {
+        let mut info = HexWriter(self.reply);
+
+        let _ = if id >= KERNEL_THREAD {
+            write!(info, "kernel on core {}", id - KERNEL_THREAD)
+        } else {
+            write!(info, "task {}", id)
+        };
+    }
+
+    // Handles one packet, returning false when the target should resume
+    fn command(&mut self, packet: &[u8]) -> bool {
+        self.reply.clear();
+
+        let (&command, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return true,
+        };
+
+        let ok = match command {
+            b'?' => {
+                self.stop_reply();
+                return true;
+            }
+            b'g' => {
+                self.read_registers();
+                return true;
+            }
+            b'G' => self.write_registers(args),
+            b'p' => match parse_hex(args) {
+                Some(index) if index < REGISTERS => {
+                    let regs = match &self.selected {
+                        Some(thread) => thread.regs,
+                        None => registers(self.frame, self.regs),
+                    };
+                    let bytes = regs[index].to_le_bytes();
+                    self.reply.push_hex(&bytes[..register_size(index)]);
+                    return true;
+                }
+                _ => None,
+            },
+            b'P' => {
+                let mut parts = args.splitn(2, |&byte| byte == b'=');
+                let index = parts.next().and_then(parse_hex);
+                let value = parts.next();
+
+                index
+                    .zip(value)
+                    .and_then(|(index, value)| self.write_register(index, value))
+            }
+            b'm' => {
+                let mut parts = args.splitn(2, |&byte| byte == b',');
+                let addr = parts.next().and_then(parse_hex);
+                let len = parts.next().and_then(parse_hex);
+
+                match addr.zip(len) {
+                    Some((addr, len)) if self.read_memory(addr, len).is_some() => return true,
+                    _ => {
+                        self.reply.clear();
+                        None
+                    }
+                }
+            }
+            b'M' => {
+                let mut parts = args.splitn(2, |&byte| byte == b':');
+                let header = parts.next().unwrap_or(&[]);
+                let data = parts.next().unwrap_or(&[]);
+
+                let mut header = header.splitn(2, |&byte| byte == b',');
+                let addr = header.next().and_then(parse_hex);
+                let len = header.next().and_then(parse_hex);
+
+                match addr.zip(len) {
+                    Some((addr, len)) if data.len() == len * 2 => self.write_memory(addr, data),
+                    _ => None,
+                }
+            }
+            b'Z' | b'z' if args.starts_with(b"0,") => {
+                let mut parts = args[2..].splitn(2, |&byte| byte == b',');
+                match parts.next().and_then(parse_hex) {
+                    Some(addr) if command == b'Z' => self.insert_breakpoint(addr),
+                    Some(addr) => self.remove_breakpoint(addr),
+                    None => None,
+                }
+            }
+            b'H' if args.first() == Some(&b'g') => {
+                let id = &args[1..];
+                if id == b"0" || id == b"-1" {
+                    self.selected = None;
+                    Some(())
+                } else {
+                    match parse_hex(id) {
+                        Some(id) if id == self.current => {
+                            self.selected = None;
+                            Some(())
+                        }
+                        Some(id) => find_thread(id).map(|thread| self.selected = Some(thread)),
+                        None => None,
+                    }
+                }
+            }
+            // Resuming always resumes whatever stopped
+            b'H' => Some(()),
+            b'T' => match parse_hex(args) {
+                Some(id) if self.thread_alive(id) => Some(()),
+                _ => None,
+            },
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    self.frame.rip = addr as u64;
+                }
+
+                if command == b's' {
+                    self.frame.rflags |= RFLAGS_TF;
+                } else {
+                    self.frame.rflags &= !RFLAGS_TF;
+                }
+
+                RESUMED.store(true, Ordering::SeqCst);
+                return false;
+            }
+            b'D' => {
+                self.frame.rflags &= !RFLAGS_TF;
+                self.reply.push(b"OK");
+                self.reply.send();
+                return false;
+            }
+            // There is nothing to kill, let it run like a detach
+            b'k' => return false,
+            b'q' => {
+                if packet.starts_with(b"qSupported") {
+                    let _ = write!(self.reply, "PacketSize={:x};swbreak+", MAX_PACKET);
+                } else if packet == b"qfThreadInfo" {
+                    self.thread_list();
+                } else if packet == b"qsThreadInfo" {
+                    self.reply.push(b"l");
+                } else if packet == b"qC" {
+                    let current = self.current;
+                    let _ = write!(self.reply, "QC{:x}", current);
+                } else if packet == b"qAttached" {
+                    self.reply.push(b"1");
+                } else if let Some(id) = packet.strip_prefix(b"qThreadExtraInfo,") {
+                    if let Some(id) = parse_hex(id) {
+                        self.thread_info(id);
+                    }
+                }
+
+                return true;
+            }
+            // An empty reply tells GDB we do not know the packet
+            _ => return true,
+        };
+
+        match ok {
+            Some(()) => self.reply.push(b"OK"),
+            None => self.reply.push(b"E01"),
+        }
+
+        true
+    }
+}
+
+// Waits for whoever is talking to GDB with interrupts off, so keep answering
+// shootdowns meanwhile. If the code we stopped had interrupts on they are let
+// in now and then too, so the core keeps ticking however long the session
+fn lock(frame: &InterruptFrame) -> LockCellGuard<'static, Stub> {
+    loop {
+        if let Some(stub) = STUB.try_lock() {
+            return stub;
+        }
+
+        tlb::service_pending();
+
+        if frame.rflags & RFLAGS_IF != 0 {
+            unsafe { core::arch::asm!("sti", "nop", "cli") };
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+// Talks to GDB until it resumes us. Only one core is stopped at a time, the
+// others keep running and queue up here if they stop too
+fn enter(signal: u8, frame: &mut InterruptFrame, regs: &mut Registers) {
+    let stub = lock(frame);
+    enter_locked(stub, signal, frame, regs)
+}
+
+fn enter_locked(
+    mut stub: LockCellGuard<'static, Stub>,
+    signal: u8,
+    frame: &mut InterruptFrame,
+    regs: &mut Registers,
+) {
+    let Stub {
+        packet,
+        reply,
+        breakpoints,
+    } = &mut *stub;
+
+    // Whatever we stopped in may hold our task list, kernel code that is
+    let task = if frame.cs & 0x3 == 0x3 {
+        core!()
+            .tasks
+            .try_lock()
+            .zip(core!().current_task_id.try_lock())
+            .and_then(|(tasks, current_task_id)| tasks.get(*current_task_id).map(|task| task.id()))
+    } else {
+        None
+    };
+    let current = task.unwrap_or(KERNEL_THREAD + core!().id);
+
+    let mut session = Session {
+        reply,
+        breakpoints,
+        frame,
+        regs,
+        signal,
+        current,
+        selected: None,
+        page_table: cpu::read_cr3() as usize & 0xffffffffff000,
+    };
+
+    // GDB only waits for a stop reply once it told us to go
+    if RESUMED.swap(false, Ordering::SeqCst) {
+        session.reply.clear();
+        session.stop_reply();
+        session.reply.send();
+    }
+
+    loop {
+        let len = read_packet(packet, session.reply);
+        if !session.command(&packet[..len]) {
+            break;
+        }
+
+        session.reply.send();
+    }
+}
+
+// Uses COM2 for the debugger, which is fine to not have
+pub fn init() -> Option<()> {
+    COM2.init()?;
+    irq::register_isa(COM2_IRQ, interrupt)?;
+    COM2.enable_receive_interrupt();
+
+    PRESENT.store(true, Ordering::SeqCst);
+
+    Some(())
+}
+
+// GDB sends something while we run, usually Ctrl-C, so stop and listen. The
+// stub reads the bytes itself
+fn interrupt(_gsi: u32, interrupt: &mut Interrupt) {
+    enter(SIGINT, interrupt.frame, interrupt.regs);
+}
+
+// Called for #DB and #BP, returns false if there is no debugger to take them
+pub fn handle_trap(vector: u8, frame: &mut InterruptFrame, regs: &mut Registers) -> bool {
+    if !PRESENT.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let stub = match vector {
+        1 => {
+            frame.rflags &= !RFLAGS_TF;
+            lock(frame)
+        }
+        3 => {
+            let addr = (frame.rip as usize).wrapping_sub(1);
+            let table = cpu::read_cr3() as usize & 0xffffffffff000;
+
+            // A task that stops while another core talks to GDB makes room for
+            // the rest of our queue rather than wait, and runs into the int3
+            // again once it is back
+            let stub = match STUB.try_lock() {
+                Some(stub) => stub,
+                None if frame.cs & 0x3 == 0x3 && is_int3(table, addr) => {
+                    frame.rip = addr as u64;
+                    unsafe { paging::load_kernel() };
+                    sched::yield_current(frame, regs)
+                }
+                None => lock(frame),
+            };
+
+            // Back up onto the int3 if it is one of ours, GDB wants the address
+            // of the breakpoint
+            let ours = stub
+                .breakpoints
+                .iter()
+                .flatten()
+                .any(|bp| bp.matches(addr, table));
+
+            if ours {
+                frame.rip = addr as u64;
+            }
+
+            stub
+        }
+        _ => return false,
+    };
+
+    enter_locked(stub, SIGTRAP, frame, regs);
+    true
+}
+
+// Whether the trap at `addr` came from a one byte int3, rather than `int 3`,
+// so it can be run again
+fn is_int3(table: usize, addr: usize) -> bool {
+    let page_table = unsafe { PageTable::from_table(PhysAddr(table)) };
+    let mut byte = [0];
+
+    page_table
+        .read_virt(&mut PhysicalMemory, VirtAddr(addr), &mut byte)
+        .is_some()
+        && byte[0] == INT3
+}
@@ -0,0 +1,187 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::interrupts::Registers;
+use crate::ipc::{ChannelEnd, Reply};
+use crate::shm::SharedMemory;
+use crate::syscall::ERROR;
+use crate::task::{self, Process};
+
+// Handles are what tasks name kernel objects by. 0 is never a valid handle
+pub type Handle = u64;
+
+// What a handle lets its holder do with the object behind it
+pub type Rights = u64;
+
+pub const RIGHT_READ: Rights = 1 << 0;
+pub const RIGHT_WRITE: Rights = 1 << 1;
+pub const RIGHT_EXECUTE: Rights = 1 << 2;
+pub const RIGHT_MAP: Rights = 1 << 3;
+pub const RIGHT_DUPLICATE: Rights = 1 << 4;
+
+pub const RIGHTS_ALL: Rights =
+    RIGHT_READ | RIGHT_WRITE | RIGHT_EXECUTE | RIGHT_MAP | RIGHT_DUPLICATE;
+
+// Anything that moves bytes in and out, e.g. the serial port. Neither call
+// blocks, they return how many bytes were moved
+pub trait Device: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> Option<usize>;
+    fn write(&self, buf: &[u8]) -> Option<usize>;
+}
+
+#[derive(Clone)]
+pub enum Object {
+    // One side of a channel
+    Channel(ChannelEnd),
+    // The right to answer one `call`
+    Reply(Arc<Reply>),
+    Memory(Arc<SharedMemory>),
+    // A thread by id and the process it belongs to. The process stays around
+    // while the handle does, the thread itself is owned by the scheduler
+    #[allow(dead_code)]
+    Task(Arc<Process>, usize),
+    Device(Arc<dyn Device>),
+    // The kernel log, read with `klog`
+    Log,
+}
+
+#[derive(Clone)]
+struct Entry {
+    object: Object,
+    rights: Rights,
+}
+
+#[derive(Default)]
+pub struct HandleTable {
+    slots: Vec<Option<Entry>>,
+}
+
+impl HandleTable {
+    pub fn insert(&mut self, object: Object, rights: Rights) -> Handle {
+        let entry = Entry { object, rights };
+
+        let index = match self.slots.iter().position(Option::is_none) {
+            Some(index) => {
+                self.slots[index] = Some(entry);
+                index
+            }
+            None => {
+                self.slots.push(Some(entry));
+                self.slots.len() - 1
+            }
+        };
+
+        index as Handle + 1
+    }
+
+    fn entry(&self, handle: Handle) -> Option<&Entry> {
+        self.slots.get((handle as usize).checked_sub(1)?)?.as_ref()
+    }
+
+    // The object behind `handle`, if the handle has at least `rights`
+    pub fn get(&self, handle: Handle, rights: Rights) -> Option<&Object> {
+        let entry = self.entry(handle)?;
+
+        if entry.rights & rights != rights {
+            return None;
+        }
+
+        Some(&entry.object)
+    }
+
+    pub fn rights(&self, handle: Handle) -> Option<Rights> {
+        self.entry(handle).map(|entry| entry.rights)
+    }
+
+    pub fn remove(&mut self, handle: Handle) -> Option<Object> {
+        self.slots
+            .get_mut((handle as usize).checked_sub(1)?)?
+            .take()
+            .map(|entry| entry.object)
+    }
+
+    // A new handle to the same object, with at most the rights of the old one
+    pub fn duplicate(&mut self, handle: Handle, rights: Rights) -> Option<Handle> {
+        let entry = self.entry(handle)?.clone();
+
+        if entry.rights & RIGHT_DUPLICATE == 0 || entry.rights & rights != rights {
+            return None;
+        }
+
+        Some(self.insert(entry.object, rights))
+    }
+}
+
+// handle_duplicate(handle, rights), returns the new handle
+pub fn sys_duplicate(regs: &mut Registers) {
+    regs.rax =
+        task::with_current(|task| task.handles().duplicate(regs.rdi, regs.rsi)).unwrap_or(ERROR);
+}
+
+// handle_close(handle), the object goes away with its last handle
+pub fn sys_close(regs: &mut Registers) {
+    // Dropped out here, closing a channel end can wake its peer
+    let object = task::with_current(|task| task.handles().remove(regs.rdi));
+    regs.rax = if object.is_some() { 0 } else { ERROR };
+}
+
+// handle_rights(handle)
+pub fn sys_rights(regs: &mut Registers) {
+    regs.rax = task::with_current(|task| task.handles().rights(regs.rdi)).unwrap_or(ERROR);
+}
+
+// task_self(), returns a handle to the calling task
+pub fn sys_task_self(regs: &mut Registers) {
+    regs.rax = task::with_current(|task| {
+        task.handles().insert(
+            Object::Task(task.process(), task.id()),
+            RIGHT_READ | RIGHT_DUPLICATE,
+        )
+    });
+}
+
+// task_id(task), returns the id of the task behind a handle
+pub fn sys_task_id(regs: &mut Registers) {
+    regs.rax = task::with_current(|task| match task.handles().get(regs.rdi, RIGHT_READ) {
+        Some(Object::Task(_, id)) => *id as u64,
+        _ => ERROR,
+    });
+}
+
+fn device(regs: &Registers, rights: Rights) -> Option<Arc<dyn Device>> {
+    task::with_current(|task| match task.handles().get(regs.rdi, rights)? {
+        Object::Device(device) => Some(device.clone()),
+        _ => None,
+    })
+}
+
+fn read(regs: &Registers) -> Option<u64> {
+    let device = device(regs, RIGHT_READ)?;
+    let len = (regs.rdx as usize).min(4096);
+
+    let mut buf = alloc::vec![0; len];
+    let read = device.read(&mut buf)?;
+    task::with_current(|task| task.write_user(regs.rsi, &buf[..read]))?;
+
+    Some(read as u64)
+}
+
+fn write(regs: &Registers) -> Option<u64> {
+    let device = device(regs, RIGHT_WRITE)?;
+    let len = (regs.rdx as usize).min(4096);
+
+    let mut buf = alloc::vec![0; len];
+    task::with_current(|task| task.read_user(regs.rsi, &mut buf))?;
+
+    device.write(&buf).map(|written| written as u64)
+}
+
+// read(handle, buf, len), returns how much was read, at most a page at a time
+pub fn sys_read(regs: &mut Registers) {
+    regs.rax = read(regs).unwrap_or(ERROR);
+}
+
+// write(handle, buf, len), returns how much was written
+pub fn sys_write(regs: &mut Registers) {
+    regs.rax = write(regs).unwrap_or(ERROR);
+}
@@ -1,7 +1,33 @@
 #![allow(dead_code)]
 
-use crate::mm::PhysMem;
+use crate::core_locals;
+use crate::exceptions;
+use crate::irq;
+use crate::mm::{self, PhysMem};
+use alloc::boxed::Box;
 use core::alloc::Layout;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+// IST indices (1-based, 0 means no IST) for faults that must never run on a
+// possibly broken kernel stack
+pub const IST_DOUBLE_FAULT: u8 = 1;
+pub const IST_NMI: u8 = 2;
+pub const IST_MACHINE_CHECK: u8 = 3;
+
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+// Stack of each core, for the scheduler to run on between tasks. User mode
+// enters the kernel on the stack of the running thread instead
+const KERNEL_STACK_SIZE: usize = 64 * 1024;
+
+// Offset of rsp0 in the TSS, which is packed
+const TSS_RSP0: usize = 4;
+
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+// Vectors handed out by `Interrupts::allocate_vector`
+pub const DYNAMIC_VECTORS: Range<u8> = 0x30..0xf0;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -147,6 +173,217 @@ macro_rules! handler_errorcode {
     }};
 }
 
+// Every vector gets a 16 byte stub that pushes its vector number in place of
+// an error code, the common entry then mirrors `handler_errorcode!`
+core::arch::global_asm!(
+    r#"
+    .pushsection .text
+    .align 16
+    .global interrupt_stubs
+interrupt_stubs:
+    .set vector, 0
+    .rept 256
+    .align 16
+    push vector
+    jmp interrupt_common
+    .set vector, vector + 1
+    .endr
+
+interrupt_common:
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rbp
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    mov rsi, [rsp + 15*8]
+    mov rdi, rsp
+    add rdi, 16*8
+    mov rdx, rsp
+    sub rsp, 8
+    call {dispatch}
+    add rsp, 8
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rbp
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+
+    add rsp, 8
+    iretq
+    .popsection
+"#,
+    dispatch = sym dispatch
+);
+
+extern "C" {
+    fn interrupt_stubs();
+}
+
+fn stub_address(vector: u8) -> u64 {
+    interrupt_stubs as *const () as u64 + vector as u64 * 16
+}
+
+pub struct Interrupt<'a> {
+    pub vector: u8,
+    pub frame: &'a mut InterruptFrame,
+    pub regs: &'a mut Registers,
+}
+
+pub type InterruptHandler = fn(interrupt: &mut Interrupt, context: *mut ());
+
+enum Handler {
+    Function(InterruptHandler, *mut ()),
+    Closure(Box<dyn Fn(&mut Interrupt) + Send + Sync>),
+}
+
+struct Vector {
+    allocated: AtomicBool,
+    handler: AtomicPtr<Handler>,
+
+    // Number of handlers currently running, so unregistering can wait for them
+    // before freeing the handler
+    active: AtomicUsize,
+
+    // Low half of the gate registered for this vector, 0 while it has the
+    // default one. Cores coming up later start from this
+    gate: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_VECTOR: Vector = Vector {
+    allocated: AtomicBool::new(false),
+    handler: AtomicPtr::new(core::ptr::null_mut()),
+    active: AtomicUsize::new(0),
+    gate: AtomicU64::new(0),
+};
+
+// Handlers are shared by all cores, only the gates live in the per core IDT
+static VECTORS: [Vector; 256] = [FREE_VECTOR; 256];
+
+// Writes the gate for a dynamic vector into the IDT of every core. Only the low
+// half holds the options, the stub address in the high half never changes, so
+// a single store updates a gate another core may be using
+fn set_gate(vector: u8, options: GateOptions) {
+    let gate = IDTDescriptor::from_raw(
+        options.ist,
+        options.type_attributes(),
+        0x08,
+        stub_address(vector),
+    )
+    .to_u128() as u64;
+
+    VECTORS[vector as usize].gate.store(gate, Ordering::SeqCst);
+
+    for core in core_locals::cores() {
+        let idt = core.idt.load(Ordering::SeqCst);
+        if !idt.is_null() {
+            unsafe { core::ptr::write_volatile(idt.add(vector as usize) as *mut u64, gate) };
+        }
+    }
+}
+
+// Where user mode enters the kernel on this core from now on
+pub fn set_kernel_stack(top: mm::VirtAddr) {
+    let tss = core!().tss.load(Ordering::SeqCst);
+
+    unsafe { core::ptr::write_unaligned(tss.add(TSS_RSP0) as *mut u64, top.0 as u64) };
+}
+
+extern "C" fn dispatch(frame: &mut InterruptFrame, vector: u64, regs: &mut Registers) {
+    let user = frame.cs & 0x3 == 0x3;
+
+    if user {
+        unsafe { core::arch::asm!("swapgs") };
+    }
+
+    let slot = &VECTORS[vector as usize];
+    slot.active.fetch_add(1, Ordering::SeqCst);
+
+    let mut interrupt = Interrupt {
+        vector: vector as u8,
+        frame,
+        regs,
+    };
+
+    match unsafe { slot.handler.load(Ordering::SeqCst).as_ref() } {
+        Some(Handler::Function(handler, context)) => handler(&mut interrupt, *context),
+        Some(Handler::Closure(handler)) => handler(&mut interrupt),
+        None => {
+            log::warn!("Unhandled interrupt on vector {:#x}", vector);
+
+            // Without an EOI the local APIC holds back everything at this
+            // priority or lower on this core for good
+            if vector >= 32 {
+                crate::apic::eoi();
+            }
+        }
+    }
+
+    slot.active.fetch_sub(1, Ordering::SeqCst);
+
+    if user {
+        // Left until no handler is running, switching away never returns
+        if core!().preempt.swap(false, Ordering::SeqCst) {
+            unsafe { crate::paging::load_kernel() };
+            crate::sched::yield_current(frame, regs);
+        }
+
+        unsafe { core::arch::asm!("swapgs") };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GateType {
+    Interrupt = 0xe,
+    Trap = 0xf,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GateOptions {
+    pub gate: GateType,
+    pub ist: u8,
+    pub dpl: u8,
+}
+
+impl Default for GateOptions {
+    fn default() -> Self {
+        Self {
+            gate: GateType::Interrupt,
+            ist: 0,
+            dpl: 0,
+        }
+    }
+}
+
+impl GateOptions {
+    fn type_attributes(&self) -> u8 {
+        0x80 | ((self.dpl & 0x3) << 5) | self.gate as u8
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct IDTDescriptor {
@@ -172,13 +409,17 @@ pub enum ISTType {
 
 impl IDTDescriptor {
     pub fn new(ist: u8, typ: ISTType, gdt_selector: u16, handler: extern "C" fn()) -> Self {
+        Self::from_raw(ist, typ as u8, gdt_selector, handler as u64)
+    }
+
+    pub fn from_raw(ist: u8, type_attributes: u8, gdt_selector: u16, handler: u64) -> Self {
         Self {
             code_selector: gdt_selector,
-            base_low: handler as u64 as u16,
-            base_mid: ((handler as u64) >> 16) as u16,
-            base_high: ((handler as u64) >> 32) as u32,
+            base_low: handler as u16,
+            base_mid: (handler >> 16) as u16,
+            base_high: (handler >> 32) as u32,
             ist,
-            type_attributes: typ as u8,
+            type_attributes,
             reserved: 0,
         }
     }
@@ -209,7 +450,6 @@ impl Tss {
 pub struct Interrupts {
     gdt: &'static mut [u64],
     tss: &'static mut [u64],
-    idt: &'static mut [u128],
 }
 
 impl Interrupts {
@@ -220,18 +460,30 @@ impl Interrupts {
 
         let tss = unsafe { core::slice::from_raw_parts_mut(tss_page.0 as *mut u64, 13) };
 
+        let mut ist = [0u64; 7];
+        for ist_type in [IST_DOUBLE_FAULT, IST_NMI, IST_MACHINE_CHECK] {
+            let stack = mm::alloc_kernel_stack(allocator, IST_STACK_SIZE)
+                .expect("Failed to allocate IST stack");
+            ist[ist_type as usize - 1] = stack.0 as u64;
+        }
+
+        let kernel_stack = mm::alloc_kernel_stack(allocator, KERNEL_STACK_SIZE)
+            .expect("Failed to allocate kernel stack");
+
         tss.copy_from_slice(
             &Tss {
-                rsp: [
-                    &crate::STACK[crate::STACK.len() - 1] as *const u8 as u64,
-                    0,
-                    0,
-                ],
+                rsp: [kernel_stack.0 as u64, 0, 0],
+                ist,
                 ..Default::default()
             }
             .into_slice(),
         );
 
+        core!().stack.store(kernel_stack.0, Ordering::SeqCst);
+        core!()
+            .tss
+            .store(tss.as_mut_ptr() as *mut u8, Ordering::SeqCst);
+
         let gdt_page = allocator
             .alloc_phys_zeroed(Layout::from_size_align(4096, 4096).unwrap())
             .unwrap();
@@ -291,59 +543,165 @@ impl Interrupts {
             core::arch::asm!("lidt [{}]", in(reg) &idtr);
         }
 
-        idt[0x8] = IDTDescriptor::new(
-            0,
-            ISTType::KernelModeIntGate,
-            0x08,
-            handler_errorcode!(double_fault),
-        )
-        .to_u128();
-        idt[0xe] = IDTDescriptor::new(
-            0,
-            ISTType::KernelModeIntGate,
+        macro_rules! exception_gates {
+            ($handler:ident, $func:ident: $($vector:literal)*) => {
+                $(
+                    idt[$vector] = IDTDescriptor::new(
+                        exception_ist($vector),
+                        ISTType::KernelModeIntGate,
+                        0x08,
+                        $handler!(exceptions::$func::<$vector>),
+                    )
+                    .to_u128();
+                )*
+            };
+        }
+
+        exception_gates!(handler, exception:
+            0 1 2 3 4 5 6 7 9 15 16 18 19 20 22 23 24 25 26 27 28 31);
+        exception_gates!(handler_errorcode, exception_errorcode:
+            8 10 11 12 13 14 17 21 29 30);
+
+        // User code may int3 too, for breakpoints set by the debugger
+        idt[3] = IDTDescriptor::new(
+            exception_ist(3),
+            ISTType::UserModeIntGate,
             0x08,
-            handler_errorcode!(page_fault),
+            handler!(exceptions::exception::<3>),
         )
         .to_u128();
-        idt[0x80] = IDTDescriptor::new(
+
+        // Everything else goes through the stubs until someone registers it
+        for vector in 32..=255 {
+            idt[vector as usize] = IDTDescriptor::from_raw(
+                0,
+                GateOptions::default().type_attributes(),
+                0x08,
+                stub_address(vector),
+            )
+            .to_u128();
+        }
+
+        let spurious =
+            IDTDescriptor::new(0, ISTType::KernelModeIntGate, 0x08, handler!(irq::spurious))
+                .to_u128();
+        let pic_base = crate::pic::PIC_VECTOR_BASE as usize;
+        for vector in pic_base..pic_base + 16 {
+            idt[vector] = spurious;
+        }
+        idt[crate::apic::SPURIOUS_VECTOR as usize] = spurious;
+
+        idt[SYSCALL_VECTOR as usize] = IDTDescriptor::new(
             0,
             ISTType::UserModeIntGate,
             0x08,
-            handler!(crate::timer_int),
+            handler!(crate::syscall::handler),
         )
         .to_u128();
 
-        Self { gdt, idt, tss }
+        // From here on registering a vector updates our IDT too, pick up what
+        // was registered before we came up
+        core!().idt.store(idt.as_mut_ptr(), Ordering::SeqCst);
+        for vector in DYNAMIC_VECTORS {
+            let gate = VECTORS[vector as usize].gate.load(Ordering::SeqCst);
+            if gate != 0 {
+                idt[vector as usize] = (idt[vector as usize] & !(u64::MAX as u128)) | gate as u128;
+            }
+        }
+
+        Self { gdt, tss }
     }
-}
 
-extern "C" fn page_fault(frame: &InterruptFrame, error_code: u64, regs: &Registers) {
-    if frame.cs & 0x3 == 0x3 {
-        unsafe { core::arch::asm!("swapgs") };
+    pub fn allocate_vector(&mut self) -> Option<u8> {
+        DYNAMIC_VECTORS
+            .filter(|&vector| vector != SYSCALL_VECTOR)
+            .find(|&vector| {
+                VECTORS[vector as usize]
+                    .allocated
+                    .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            })
     }
 
-    unsafe {
-        let kernel_page_table = core!().kernel_page_table.lock();
-        let kernel_page_table = kernel_page_table.as_ref().unwrap();
-        kernel_page_table.switch_to();
+    pub fn free_vector(&mut self, vector: u8) {
+        self.unregister(vector);
+        VECTORS[vector as usize]
+            .allocated
+            .store(false, Ordering::SeqCst);
     }
 
-    panic!("Page fault {:#x}\n{:#x?}\n{:#x?}", error_code, frame, regs);
-}
+    // Calls `handler` with `context` whenever `vector` fires
+    pub fn register(
+        &mut self,
+        vector: u8,
+        options: GateOptions,
+        handler: InterruptHandler,
+        context: *mut (),
+    ) -> Option<()> {
+        self.install(vector, options, Handler::Function(handler, context))
+    }
 
-extern "C" fn double_fault(frame: &InterruptFrame, error_code: u64, regs: &Registers) {
-    if frame.cs & 0x3 == 0x3 {
-        unsafe { core::arch::asm!("swapgs") };
+    pub fn register_closure(
+        &mut self,
+        vector: u8,
+        options: GateOptions,
+        handler: impl Fn(&mut Interrupt) + Send + Sync + 'static,
+    ) -> Option<()> {
+        self.install(vector, options, Handler::Closure(Box::new(handler)))
+    }
+
+    fn install(&mut self, vector: u8, options: GateOptions, handler: Handler) -> Option<()> {
+        // Exceptions and the syscall gate have their own handlers
+        if vector < 32 || vector == SYSCALL_VECTOR {
+            return None;
+        }
+
+        let handler = Box::into_raw(Box::new(handler));
+        if VECTORS[vector as usize]
+            .handler
+            .compare_exchange(
+                core::ptr::null_mut(),
+                handler,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_err()
+        {
+            drop(unsafe { Box::from_raw(handler) });
+            return None;
+        }
+
+        set_gate(vector, options);
+
+        Some(())
     }
 
-    unsafe {
-        let kernel_page_table = core!().kernel_page_table.lock();
-        let kernel_page_table = kernel_page_table.as_ref().unwrap();
-        kernel_page_table.switch_to();
+    pub fn unregister(&mut self, vector: u8) {
+        let slot = &VECTORS[vector as usize];
+        let handler = slot.handler.swap(core::ptr::null_mut(), Ordering::SeqCst);
+
+        if handler.is_null() {
+            return;
+        }
+
+        set_gate(vector, GateOptions::default());
+
+        // Wait for other cores still running the old handler, which also means
+        // a handler must never unregister itself
+        while slot.active.load(Ordering::SeqCst) != 0 {
+            core::hint::spin_loop();
+        }
+
+        drop(unsafe { Box::from_raw(handler) });
     }
+}
 
-    panic!(
-        "Double fault {:#x}\n{:#x?}\n{:#x?}",
-        error_code, frame, regs
-    );
+// Faults that must never run on a possibly broken kernel stack get their own
+const fn exception_ist(vector: u8) -> u8 {
+    match vector {
+        0x2 => IST_NMI,
+        0x8 => IST_DOUBLE_FAULT,
+        0x12 => IST_MACHINE_CHECK,
+        _ => 0,
+    }
 }
@@ -0,0 +1,564 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    handle::{Object, Rights, RIGHT_DUPLICATE, RIGHT_READ, RIGHT_WRITE},
+    interrupts::{InterruptFrame, Registers},
+    mm::{self, PhysAddr, PhysMem},
+    sched, shm,
+    sync::IrqLockCell,
+    syscall::ERROR,
+    task::{self, Task},
+};
+
+// Payloads bigger than this have to go as pages
+pub const MAX_MESSAGE_BYTES: usize = 4096;
+pub const MAX_MESSAGE_PAGES: usize = 256;
+
+// Messages waiting on one side of a channel before `send` fails
+pub const MAX_QUEUED: usize = 64;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// Returned by send, receive and call once the other side of the channel has
+// no ends left, after anything it sent before was received
+pub const PEER_CLOSED: u64 = 1;
+
+// How user mode describes a message, both to send one and to receive one. On
+// receive `len` and `page_count` say how much fits and are updated to what
+// arrived
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct UserMessage {
+    pub tag: u64,
+    pub data: u64,
+    pub len: u64,
+
+    // Page aligned start of whole pages that move with the message, they are
+    // unmapped from the sender and mapped into the receiver
+    pub pages: u64,
+    pub page_count: u64,
+}
+
+pub struct Message {
+    tag: u64,
+    data: Vec<u8>,
+    pages: Vec<PhysAddr>,
+    reply: Option<Arc<Reply>>,
+}
+
+// Pages of a message nobody received go back to the allocator
+impl Drop for Message {
+    fn drop(&mut self) {
+        for &frame in &self.pages {
+            mm::PhysicalMemory.free_phys(frame, PAGE_SIZE as usize);
+        }
+    }
+}
+
+// A blocked task and where the message it waits for goes
+struct Waiter {
+    task: Box<Task>,
+    message: u64,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Message>,
+    receiver: Option<Waiter>,
+
+    // This side has no ends left, so nothing sent to it is ever received
+    closed: bool,
+    // The other side has no ends left, so nothing more arrives
+    peer_closed: bool,
+}
+
+// Two connected endpoints, what is sent on one side is received on the other
+pub struct Channel {
+    queues: [IrqLockCell<Queue>; 2],
+
+    // Ends open on each side, a side closes when its count drops to 0
+    ends: [AtomicUsize; 2],
+}
+
+impl Channel {
+    // Returns both ends of a new channel
+    pub fn create() -> (ChannelEnd, ChannelEnd) {
+        let channel = Arc::new(Channel {
+            queues: [
+                IrqLockCell::new(Queue::default()),
+                IrqLockCell::new(Queue::default()),
+            ],
+            ends: [AtomicUsize::new(1), AtomicUsize::new(1)],
+        });
+
+        let first = ChannelEnd {
+            channel: channel.clone(),
+            side: 0,
+        };
+        let second = ChannelEnd { channel, side: 1 };
+
+        (first, second)
+    }
+
+    // Fails whoever waits on either side, the tasks blocked in here would keep
+    // the channel and their own processes alive otherwise
+    fn close(&self, side: usize) {
+        let (receiver, messages) = {
+            let mut queue = self.queues[side].lock();
+            queue.closed = true;
+
+            (queue.receiver.take(), core::mem::take(&mut queue.messages))
+        };
+
+        // Another thread of the receiving process closed the handle it waits on
+        if let Some(mut receiver) = receiver {
+            receiver.task.set_return(ERROR, 0);
+            sched::enqueue(receiver.task);
+        }
+
+        // Calls still queued here are never answered
+        for message in &messages {
+            if let Some(reply) = &message.reply {
+                reply.state.lock().peer_closed = true;
+            }
+        }
+        drop(messages);
+
+        let receiver = {
+            let mut queue = self.queues[side ^ 1].lock();
+            queue.peer_closed = true;
+            queue.receiver.take()
+        };
+
+        if let Some(mut receiver) = receiver {
+            receiver.task.set_return(PEER_CLOSED, 0);
+            sched::enqueue(receiver.task);
+        }
+    }
+}
+
+// One side of a channel, as handles hold it. Copies count as open ends too
+pub struct ChannelEnd {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+impl Clone for ChannelEnd {
+    fn clone(&self) -> Self {
+        self.channel.ends[self.side].fetch_add(1, Ordering::SeqCst);
+
+        ChannelEnd {
+            channel: self.channel.clone(),
+            side: self.side,
+        }
+    }
+}
+
+impl Drop for ChannelEnd {
+    fn drop(&mut self) {
+        if self.channel.ends[self.side].fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.channel.close(self.side);
+        }
+    }
+}
+
+// Where the answer to one `call` goes. Dropping it unanswered fails the call
+pub struct Reply {
+    state: IrqLockCell<ReplyState>,
+}
+
+#[derive(Default)]
+struct ReplyState {
+    message: Option<Message>,
+    caller: Option<Waiter>,
+
+    // The call was never received, its channel side closed with it queued
+    peer_closed: bool,
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        let error = if state.peer_closed {
+            PEER_CLOSED
+        } else {
+            ERROR
+        };
+
+        if let Some(mut caller) = state.caller.take() {
+            caller.task.set_return(error, 0);
+            sched::enqueue(caller.task);
+        }
+    }
+}
+
+// Builds a message out of the sender's memory, taking its pages
+fn gather(task: &mut Task, message: u64) -> Option<Message> {
+    let desc: UserMessage = unsafe { task.read_user_val(message)? };
+
+    if desc.len as usize > MAX_MESSAGE_BYTES
+        || desc.page_count as usize > MAX_MESSAGE_PAGES
+        || desc.pages % PAGE_SIZE != 0
+    {
+        return None;
+    }
+
+    let mut data = vec![0; desc.len as usize];
+    task.read_user(desc.data, &mut data)?;
+
+    // Check every page first, a bad range must leave the sender untouched.
+    // Shared memory stays where it is, it is not the sender's to give away
+    let page = |index: u64| desc.pages + index * PAGE_SIZE;
+    for index in 0..desc.page_count {
+        task.read_user(page(index), &mut [0])?;
+
+        if shm::is_shared(task, page(index)) {
+            return None;
+        }
+    }
+
+    let pages = (0..desc.page_count)
+        .map(|index| task.unmap_user(page(index)))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(Message {
+        tag: desc.tag,
+        data,
+        pages,
+        reply: None,
+    })
+}
+
+// Copies `message` into `task` as its `UserMessage` at `dest` describes,
+// returning the reply handle for calls. A message that does not fit is handed
+// back so it can be received again
+fn deliver(task: &mut Task, dest: u64, mut message: Message) -> Result<u64, Message> {
+    let mut desc: UserMessage = match unsafe { task.read_user_val(dest) } {
+        Some(desc) => desc,
+        None => return Err(message),
+    };
+
+    if message.data.len() > desc.len as usize
+        || message.pages.len() > desc.page_count as usize
+        || task.write_user(desc.data, &message.data).is_none()
+    {
+        return Err(message);
+    }
+
+    for (index, &frame) in message.pages.iter().enumerate() {
+        let vaddr = desc.pages + index as u64 * PAGE_SIZE;
+
+        if task.map_user(vaddr, frame, true, false).is_none() {
+            for index in 0..index as u64 {
+                task.unmap_user(desc.pages + index * PAGE_SIZE);
+            }
+
+            return Err(message);
+        }
+    }
+
+    desc.tag = message.tag;
+    desc.len = message.data.len() as u64;
+    desc.page_count = message.pages.len() as u64;
+    let _ = task.write_user_val(dest, &desc);
+
+    // The pages belong to the receiver now
+    message.pages.clear();
+
+    Ok(message
+        .reply
+        .take()
+        .map(|reply| task.handles().insert(Object::Reply(reply), RIGHT_WRITE))
+        .unwrap_or(0))
+}
+
+// Hands `message` to a blocked task and wakes it up. If it does not fit the
+// task gets an error and the message comes back
+fn complete(mut waiter: Waiter, message: Message) -> Option<Message> {
+    let leftover = match deliver(&mut waiter.task, waiter.message, message) {
+        Ok(reply) => {
+            waiter.task.set_return(0, reply);
+            None
+        }
+        Err(message) => {
+            waiter.task.set_return(ERROR, 0);
+            Some(message)
+        }
+    };
+
+    sched::enqueue(waiter.task);
+    leftover
+}
+
+// Queues `message` for the other side, or hands it back if that side is
+// closed. It is dropped outside the queue lock, its reply may wake a caller
+fn send(channel: &Channel, side: usize, message: Message) -> Result<(), Message> {
+    let mut queue = channel.queues[side ^ 1].lock();
+
+    if queue.closed {
+        return Err(message);
+    }
+
+    let message = match queue.receiver.take() {
+        Some(receiver) => complete(receiver, message),
+        None => Some(message),
+    };
+
+    if let Some(message) = message {
+        queue.messages.push_back(message);
+    }
+
+    Ok(())
+}
+
+// Whether a message can go to the other side right now, checked before taking
+// anything from the sender
+fn can_send(channel: &Channel, side: usize) -> Result<(), u64> {
+    let queue = channel.queues[side ^ 1].lock();
+
+    if queue.closed {
+        Err(PEER_CLOSED)
+    } else if queue.messages.len() >= MAX_QUEUED {
+        Err(ERROR)
+    } else {
+        Ok(())
+    }
+}
+
+fn channel(regs: &Registers, rights: Rights) -> Option<(Arc<Channel>, usize)> {
+    task::with_current(|task| match task.handles().get(regs.rdi, rights)? {
+        Object::Channel(end) => Some((end.channel.clone(), end.side)),
+        _ => None,
+    })
+}
+
+// Returns both ends in rax and rdx
+pub fn sys_channel_create(regs: &mut Registers) {
+    let (first, second) = Channel::create();
+    let rights = RIGHT_READ | RIGHT_WRITE | RIGHT_DUPLICATE;
+
+    task::with_current(|task| {
+        let mut handles = task.handles();
+        regs.rax = handles.insert(Object::Channel(first), rights);
+        regs.rdx = handles.insert(Object::Channel(second), rights);
+    });
+}
+
+fn send_message(regs: &Registers) -> Option<u64> {
+    let (channel, side) = channel(regs, RIGHT_WRITE)?;
+
+    if let Err(error) = can_send(&channel, side) {
+        return Some(error);
+    }
+
+    let message = task::with_current(|task| gather(task, regs.rsi))?;
+    match send(&channel, side, message) {
+        Ok(()) => Some(0),
+        Err(_) => Some(PEER_CLOSED),
+    }
+}
+
+// send(channel, message), fails with `PEER_CLOSED` once nobody can receive it
+pub fn sys_send(regs: &mut Registers) {
+    regs.rax = send_message(regs).unwrap_or(ERROR);
+}
+
+// receive(channel, message), blocks until there is one. A reply handle comes
+// back in rdx for calls, 0 otherwise. Once the other side is closed and
+// everything it sent was received this returns `PEER_CLOSED` instead
+pub fn sys_receive(frame: &InterruptFrame, regs: &mut Registers) {
+    let (channel, side) = match channel(regs, RIGHT_READ) {
+        Some(channel) => channel,
+        None => {
+            regs.rax = ERROR;
+            return;
+        }
+    };
+
+    let dest = regs.rsi;
+
+    let (message, peer_closed) = {
+        let mut queue = channel.queues[side].lock();
+        (queue.messages.pop_front(), queue.peer_closed)
+    };
+
+    if let Some(message) = message {
+        match task::with_current(|task| deliver(task, dest, message)) {
+            Ok(reply) => {
+                regs.rax = 0;
+                regs.rdx = reply;
+            }
+            Err(message) => {
+                channel.queues[side].lock().messages.push_front(message);
+                regs.rax = ERROR;
+            }
+        }
+
+        return;
+    }
+
+    if peer_closed {
+        regs.rax = PEER_CLOSED;
+        return;
+    }
+
+    // Nothing on this stack is dropped once we block, so the channel has to
+    // be given up in there
+    let mut channel = Some(channel);
+    sched::block_current(frame, regs, &mut |task| {
+        let channel = channel.take().unwrap();
+        let mut queue = channel.queues[side].lock();
+
+        let mut waiter = Waiter {
+            task,
+            message: dest,
+        };
+
+        if let Some(message) = queue.messages.pop_front() {
+            // It showed up while we were switching away
+            if let Some(message) = complete(waiter, message) {
+                queue.messages.push_front(message);
+            }
+        } else if queue.peer_closed {
+            waiter.task.set_return(PEER_CLOSED, 0);
+            sched::enqueue(waiter.task);
+        } else if queue.receiver.is_some() || queue.closed {
+            // Only one task can wait on a side at a time, and none on a side
+            // another thread just closed
+            waiter.task.set_return(ERROR, 0);
+            sched::enqueue(waiter.task);
+        } else {
+            queue.receiver = Some(waiter);
+        }
+    })
+}
+
+// call(channel, message, reply), sends the message and blocks until it is
+// answered. Fails with `PEER_CLOSED` if the other side closes before receiving
+// it
+pub fn sys_call(frame: &InterruptFrame, regs: &mut Registers) {
+    let (channel, side) = match channel(regs, RIGHT_READ | RIGHT_WRITE) {
+        Some(channel) => channel,
+        None => {
+            regs.rax = ERROR;
+            return;
+        }
+    };
+
+    let reply = Arc::new(Reply {
+        state: IrqLockCell::new(ReplyState::default()),
+    });
+
+    if let Err(error) = can_send(&channel, side) {
+        regs.rax = error;
+        return;
+    }
+
+    let mut message = match task::with_current(|task| gather(task, regs.rsi)) {
+        Some(message) => message,
+        None => {
+            regs.rax = ERROR;
+            return;
+        }
+    };
+
+    message.reply = Some(reply.clone());
+    if let Err(message) = send(&channel, side, message) {
+        // Nobody else holds the reply yet, so dropping it wakes no one
+        drop(message);
+        regs.rax = PEER_CLOSED;
+        return;
+    }
+    drop(channel);
+
+    let dest = regs.rdx;
+    let mut reply = Some(reply);
+    sched::block_current(frame, regs, &mut |task| {
+        let reply = reply.take().unwrap();
+        let mut state = reply.state.lock();
+
+        let waiter = Waiter {
+            task,
+            message: dest,
+        };
+
+        match state.message.take() {
+            // Answered while we were switching away
+            Some(message) => drop(complete(waiter, message)),
+            None => state.caller = Some(waiter),
+        }
+    })
+}
+
+fn reply(regs: &Registers) -> Option<u64> {
+    let (message, reply) = task::with_current(|task| {
+        if !matches!(task.handles().get(regs.rdi, RIGHT_WRITE)?, Object::Reply(_)) {
+            return None;
+        }
+
+        let message = gather(task, regs.rsi)?;
+
+        match task.handles().remove(regs.rdi)? {
+            Object::Reply(reply) => Some((message, reply)),
+            _ => unreachable!(),
+        }
+    })?;
+
+    let mut state = reply.state.lock();
+    match state.caller.take() {
+        Some(caller) => drop(complete(caller, message)),
+        None => state.message = Some(message),
+    }
+
+    Some(0)
+}
+
+// reply(reply, message), answers a call. The reply handle is used up
+pub fn sys_reply(regs: &mut Registers) {
+    regs.rax = reply(regs).unwrap_or(ERROR);
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+
+    use super::{Channel, Waiter, PEER_CLOSED};
+    use crate::handle::{Object, RIGHT_READ};
+    use crate::mm::PhysicalMemory;
+    use crate::paging::PageTable;
+    use crate::task::Task;
+
+    #[test_case]
+    fn closing_an_end_frees_the_blocked_receiver() {
+        let (first, second) = Channel::create();
+        let channel = first.channel.clone();
+
+        let page_table = PageTable::new(&mut PhysicalMemory).unwrap();
+        let mut task = Box::new(Task::new(&mut PhysicalMemory, page_table).unwrap());
+        let process = task.process();
+
+        // Nowhere to run, so waking it drops it instead
+        task.set_affinity(0);
+
+        // Blocked in receive on an end its own process holds, which is a
+        // cycle only closing the other side breaks
+        task.handles().insert(Object::Channel(second), RIGHT_READ);
+        channel.queues[1].lock().receiver = Some(Waiter { task, message: 0 });
+
+        drop(first);
+
+        assert!(channel.queues[1].lock().receiver.is_none());
+        assert_eq!(Arc::strong_count(&process), 1);
+
+        // Both sides are gone now that the process let go of its end
+        assert!(channel.queues[0].lock().peer_closed);
+        assert!(channel.queues[1].lock().closed);
+        assert_eq!(super::can_send(&channel, 0), Err(PEER_CLOSED));
+    }
+}
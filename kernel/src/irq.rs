@@ -0,0 +1,130 @@
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+
+use crate::{
+    acpi,
+    apic::{self, IoApic, Polarity, Trigger},
+    interrupts::{GateOptions, Interrupt, InterruptFrame, Registers},
+    pic,
+    sync::LockCell,
+};
+
+// Gets the interrupted state too, for the few handlers that look at it
+pub type IrqHandler = fn(gsi: u32, interrupt: &mut Interrupt);
+
+static IO_APICS: LockCell<Vec<IoApic>> = LockCell::new(Vec::new());
+
+// (GSI, vector) pairs for every routed GSI
+static ROUTES: LockCell<Vec<(u32, u8)>> = LockCell::new(Vec::new());
+
+pub fn init() -> Option<()> {
+    pic::disable();
+
+    let madt = acpi::MADT.lock();
+    let madt = madt.as_ref()?;
+
+    apic::init(madt.local_apic_address as usize);
+
+    let mut io_apics = IO_APICS.lock();
+    for info in &madt.io_apics {
+        let ioapic = unsafe { IoApic::new(info.id, info.address as usize, info.gsi_base) };
+        ioapic.mask_all();
+
+        log::info!(
+            "IO-APIC {} at {:#x} handling GSIs {}-{}",
+            ioapic.id,
+            info.address,
+            ioapic.gsi_base,
+            ioapic.gsi_base + ioapic.entries - 1
+        );
+
+        io_apics.push(ioapic);
+    }
+
+    Some(())
+}
+
+// Resolves a legacy ISA IRQ to its GSI, trigger mode and polarity, taking the
+// MADT interrupt source overrides into account
+pub fn isa_irq(irq: u8) -> (u32, Trigger, Polarity) {
+    let madt = acpi::MADT.lock();
+    let over = madt.as_ref().and_then(|madt| {
+        madt.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+    });
+
+    match over {
+        Some(over) => {
+            // Both fields are 2 bits where 0 means "conforms to the bus", which
+            // for ISA is edge triggered and active high
+            let polarity = if over.flags & 0x3 == 0x3 {
+                Polarity::ActiveLow
+            } else {
+                Polarity::ActiveHigh
+            };
+            let trigger = if (over.flags >> 2) & 0x3 == 0x3 {
+                Trigger::Level
+            } else {
+                Trigger::Edge
+            };
+
+            (over.gsi, trigger, polarity)
+        }
+        None => (irq as u32, Trigger::Edge, Polarity::ActiveHigh),
+    }
+}
+
+// Routes `gsi` to the calling core on a freshly allocated vector and starts
+// delivering it to `handler`, returning the vector
+pub fn register(gsi: u32, trigger: Trigger, polarity: Polarity, handler: IrqHandler) -> Option<u8> {
+    let mut routes = ROUTES.lock();
+    if routes.iter().any(|&(routed, _)| routed == gsi) {
+        return None;
+    }
+
+    let io_apics = IO_APICS.lock();
+    let ioapic = io_apics.iter().find(|ioapic| ioapic.handles(gsi))?;
+
+    let mut interrupts = core!().interrupt_state.lock();
+    let interrupts = interrupts.as_mut()?;
+
+    let vector = interrupts.allocate_vector()?;
+    interrupts.register_closure(vector, GateOptions::default(), move |interrupt| {
+        handler(gsi, interrupt);
+        apic::eoi();
+    })?;
+
+    ioapic.set_redirection(gsi, vector, apic::id(), trigger, polarity, false);
+    routes.push((gsi, vector));
+
+    Some(vector)
+}
+
+pub fn register_isa(irq: u8, handler: IrqHandler) -> Option<u32> {
+    let (gsi, trigger, polarity) = isa_irq(irq);
+    register(gsi, trigger, polarity, handler)?;
+    Some(gsi)
+}
+
+pub fn unregister(gsi: u32) {
+    let mut routes = ROUTES.lock();
+    let Some(index) = routes.iter().position(|&(routed, _)| routed == gsi) else {
+        return;
+    };
+    let (_, vector) = routes.remove(index);
+
+    let io_apics = IO_APICS.lock();
+    if let Some(ioapic) = io_apics.iter().find(|ioapic| ioapic.handles(gsi)) {
+        ioapic.mask(gsi);
+    }
+
+    if let Some(interrupts) = core!().interrupt_state.lock().as_mut() {
+        interrupts.free_vector(vector);
+    }
+}
+
+// Spurious interrupts from either the legacy PIC or the local APIC, neither
+// must be acknowledged
+pub extern "C" fn spurious(_frame: &InterruptFrame, _regs: &Registers) {}
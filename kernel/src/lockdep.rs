@@ -0,0 +1,224 @@
+// Lock debugging, built with the `lock_debug` feature. Every core keeps a stack
+// of the locks it holds and every "B taken while holding A" pair is recorded
+// once. Taking A while holding B is then reported as an inversion if A was
+// ever taken before B, directly or through other locks (A -> C -> B), even if
+// it never actually deadlocked. None of this may lock or allocate.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+// Indexed by APIC ID, which works before the core locals are set up
+const MAX_APIC_IDS: usize = 256;
+const MAX_HELD: usize = 32;
+const MAX_EDGES: usize = 1024;
+
+struct Held {
+    depth: AtomicUsize,
+    locks: [AtomicUsize; MAX_HELD],
+    sites: [AtomicPtr<Location<'static>>; MAX_HELD],
+}
+
+struct Edge {
+    from: AtomicUsize,
+    to: AtomicUsize,
+    site: AtomicPtr<Location<'static>>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_LOCK: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SITE: AtomicPtr<Location<'static>> = AtomicPtr::new(core::ptr::null_mut());
+#[allow(clippy::declare_interior_mutable_const)]
+const NOTHING_HELD: Held = Held {
+    depth: AtomicUsize::new(0),
+    locks: [NO_LOCK; MAX_HELD],
+    sites: [NO_SITE; MAX_HELD],
+};
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_EDGE: Edge = Edge {
+    from: AtomicUsize::new(0),
+    to: AtomicUsize::new(0),
+    site: AtomicPtr::new(core::ptr::null_mut()),
+};
+
+static HELD: [Held; MAX_APIC_IDS] = [NOTHING_HELD; MAX_APIC_IDS];
+
+static EDGES: [Edge; MAX_EDGES] = [NO_EDGE; MAX_EDGES];
+static EDGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Set once something was reported, the panic path must not trip over us again
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+fn apic_id() -> usize {
+    (crate::cpu::cpuid(1, 0).1 >> 24) as usize
+}
+
+fn held() -> &'static Held {
+    &HELD[apic_id()]
+}
+
+// Identifies the calling core in a lock's owner field, 0 means no owner
+pub fn core_token() -> usize {
+    apic_id() + 1
+}
+
+struct Site(*mut Location<'static>);
+
+impl core::fmt::Display for Site {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match unsafe { self.0.as_ref() } {
+            Some(location) => write!(f, "{}", location),
+            None => write!(f, "<unknown>"),
+        }
+    }
+}
+
+fn report(args: core::fmt::Arguments) -> ! {
+    REPORTED.store(true, Ordering::SeqCst);
+    panic!("{}", args)
+}
+
+fn find_edge(from: usize, to: usize) -> Option<&'static Edge> {
+    let count = EDGE_COUNT.load(Ordering::SeqCst).min(MAX_EDGES);
+
+    EDGES[..count].iter().find(|edge| {
+        edge.from.load(Ordering::Acquire) == from && edge.to.load(Ordering::SeqCst) == to
+    })
+}
+
+// Finds a chain of recorded edges leading from `from` to `to` and returns the
+// last one. Edges reached so far are kept in a bitmap, which grows a step
+// along every chain each time around until it stops changing
+fn find_path(from: usize, to: usize) -> Option<&'static Edge> {
+    let count = EDGE_COUNT.load(Ordering::SeqCst).min(MAX_EDGES);
+    let edges = &EDGES[..count];
+    let mut reached = [0u64; MAX_EDGES / 64];
+
+    loop {
+        let mut grew = false;
+
+        for (index, edge) in edges.iter().enumerate() {
+            if reached[index / 64] & (1 << (index % 64)) != 0 {
+                continue;
+            }
+
+            let start = edge.from.load(Ordering::Acquire);
+            let chained = start == from
+                || edges.iter().enumerate().any(|(other, prev)| {
+                    reached[other / 64] & (1 << (other % 64)) != 0
+                        && prev.to.load(Ordering::SeqCst) == start
+                });
+
+            if chained {
+                if edge.to.load(Ordering::SeqCst) == to {
+                    return Some(edge);
+                }
+
+                reached[index / 64] |= 1 << (index % 64);
+                grew = true;
+            }
+        }
+
+        if !grew {
+            return None;
+        }
+    }
+}
+
+fn add_edge(from: usize, to: usize, location: &'static Location<'static>) {
+    if find_edge(from, to).is_some() {
+        return;
+    }
+
+    // Once the table is full new orders simply go unchecked
+    let index = EDGE_COUNT.fetch_add(1, Ordering::SeqCst);
+    if let Some(edge) = EDGES.get(index) {
+        edge.site
+            .store(location as *const _ as *mut _, Ordering::SeqCst);
+        edge.to.store(to, Ordering::SeqCst);
+        edge.from.store(from, Ordering::Release);
+    }
+}
+
+// Called before spinning on `lock`, so problems are reported instead of
+// deadlocking
+pub fn before_lock(lock: usize, location: &'static Location<'static>) {
+    if REPORTED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let held = held();
+    let depth = held.depth.load(Ordering::SeqCst).min(MAX_HELD);
+
+    for index in 0..depth {
+        let other = held.locks[index].load(Ordering::SeqCst);
+        let other_site = Site(held.sites[index].load(Ordering::SeqCst));
+
+        if other == lock {
+            report(format_args!(
+                "Recursive lock of {:#x} at {}, already held since {}",
+                lock, location, other_site
+            ));
+        }
+
+        if let Some(edge) = find_path(lock, other) {
+            report(format_args!(
+                "Lock order inversion: {:#x} taken at {} while holding {:#x} \
+                 (taken at {}), but {:#x} was taken after {:#x}, last while \
+                 holding {:#x} at {}",
+                lock,
+                location,
+                other,
+                other_site,
+                other,
+                lock,
+                edge.from.load(Ordering::SeqCst),
+                Site(edge.site.load(Ordering::SeqCst))
+            ));
+        }
+
+        add_edge(other, lock, location);
+    }
+}
+
+pub fn locked(lock: usize, location: &'static Location<'static>) {
+    if REPORTED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let held = held();
+    let depth = held.depth.load(Ordering::SeqCst);
+
+    if depth < MAX_HELD {
+        held.locks[depth].store(lock, Ordering::SeqCst);
+        held.sites[depth].store(location as *const _ as *mut _, Ordering::SeqCst);
+    }
+
+    held.depth.store(depth + 1, Ordering::SeqCst);
+}
+
+pub fn unlocked(lock: usize) {
+    if REPORTED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let held = held();
+    let depth = held.depth.load(Ordering::SeqCst);
+    let tracked = depth.min(MAX_HELD);
+
+    // Guards do not have to be dropped in order
+    if let Some(index) = (0..tracked)
+        .rev()
+        .find(|&index| held.locks[index].load(Ordering::SeqCst) == lock)
+    {
+        for index in index..tracked - 1 {
+            let next_lock = held.locks[index + 1].load(Ordering::SeqCst);
+            let next_site = held.sites[index + 1].load(Ordering::SeqCst);
+
+            held.locks[index].store(next_lock, Ordering::SeqCst);
+            held.sites[index].store(next_site, Ordering::SeqCst);
+        }
+    }
+
+    held.depth.store(depth.saturating_sub(1), Ordering::SeqCst);
+}
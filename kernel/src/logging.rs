@@ -1,25 +1,283 @@
-use core::fmt::Write;
+use core::fmt::{self, Write};
 
-use crate::serial::EmergencySerial;
+use log::LevelFilter;
+
+use crate::{
+    cmdline, core_locals,
+    handle::{Object, RIGHT_READ},
+    interrupts::Registers,
+    serial::{self, SerialWriter},
+    sync::{IrqLockCell, IrqLockCellGuard},
+    syscall::ERROR,
+    task, time,
+};
+
+const LOG_BUFFER_SIZE: usize = 64 * 1024;
+
+// What everything logs at unless `log=` says otherwise
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+// Most a single `klog` copies out
+const MAX_KLOG: usize = 4096;
+
+// Everything logged so far, the same text that went out on the serial port.
+// Bytes are numbered from boot on and the oldest get overwritten
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    written: u64,
+}
+
+impl LogBuffer {
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.data[(self.written % LOG_BUFFER_SIZE as u64) as usize] = byte;
+            self.written += 1;
+        }
+    }
+
+    // Copies out what follows byte `seq`, skipping ahead if that is gone
+    // already. Returns how much was copied and where to go on from
+    fn read(&self, seq: u64, buf: &mut [u8]) -> (usize, u64) {
+        let oldest = self.written.saturating_sub(LOG_BUFFER_SIZE as u64);
+        let start = seq.clamp(oldest, self.written);
+        let len = ((self.written - start) as usize).min(buf.len());
+
+        for (offset, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.data[((start + offset as u64) % LOG_BUFFER_SIZE as u64) as usize];
+        }
+
+        (len, start + len as u64)
+    }
+}
+
+static BUFFER: IrqLockCell<LogBuffer> = IrqLockCell::new(LogBuffer {
+    data: [0; LOG_BUFFER_SIZE],
+    written: 0,
+});
+
+// Every directive that parses, as the module path it is for and its level
+fn parse_directives(directives: &str) -> impl Iterator<Item = (&str, LevelFilter)> {
+    directives
+        .split(',')
+        .filter(|directive| !directive.is_empty())
+        .filter_map(|directive| {
+            let (module, level) = directive.split_once('=').unwrap_or(("", directive));
+            Some((module, level.parse().ok()?))
+        })
+}
+
+// The level `directives` give `target`
+fn level_for(directives: &str, target: &str) -> LevelFilter {
+    let mut level = DEFAULT_LEVEL;
+    let mut best = 0;
+
+    for (module, module_level) in parse_directives(directives) {
+        let matches = module.is_empty()
+            || target == module
+            || (target.starts_with(module) && target[module.len()..].starts_with("::"));
+
+        if matches && module.len() >= best {
+            level = module_level;
+            best = module.len();
+        }
+    }
+
+    level
+}
+
+// Applies the `log=` option, e.g. `log=warn,kernel::sched=debug`. A bare level
+// is the default and otherwise the longest module path that matches wins
+pub fn init() {
+    // The `log` crate drops anything above this before asking us
+    let max = parse_directives(cmdline::get().log)
+        .map(|(_, level)| level)
+        .fold(DEFAULT_LEVEL, |max, level| max.max(level));
+    log::set_max_level(max);
+}
+
+// Sends a record to the serial port and the buffer at once, holding both for
+// the whole of it so lines from different cores never mix in either
+struct Tee {
+    buffer: IrqLockCellGuard<'static, LogBuffer>,
+    serial: SerialWriter,
+}
+
+impl Write for Tee {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.serial.write(s.as_bytes());
+        self.buffer.push(s.as_bytes());
+        Ok(())
+    }
+}
 
 pub struct Logger;
 
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= level_for(cmdline::get().log, metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
-        let mut serial = EmergencySerial;
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = time::now();
+        let core = core_locals::try_get_core_locals().map(|core| core.id);
+
+        let mut out = Tee {
+            buffer: BUFFER.lock(),
+            serial: serial::lock(),
+        };
 
-        let _ = serial.write_fmt(format_args!(
-            "{}: {}-{} {}\n",
+        let _ = write!(
+            out,
+            "[{:>5}.{:06}] ",
+            now / 1_000_000_000,
+            now / 1_000 % 1_000_000
+        );
+        let _ = match core {
+            Some(id) => write!(out, "{:<2} ", id),
+            None => write!(out, "-  "),
+        };
+        let _ = writeln!(
+            out,
+            "{:<5} {}: {}",
             record.level(),
-            record.file().unwrap_or("<unknown file>"),
-            record.line().unwrap_or(0),
+            record.target(),
             record.args()
-        ));
+        );
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        serial::COM1.flush();
+    }
+}
+
+fn klog(regs: &mut Registers) -> Option<u64> {
+    let allowed = task::with_current(|task| {
+        matches!(task.handles().get(regs.rdi, RIGHT_READ), Some(Object::Log))
+    });
+    if !allowed {
+        return None;
+    }
+
+    let mut buf = alloc::vec![0; (regs.rdx as usize).min(MAX_KLOG)];
+    let (len, next) = BUFFER.lock().read(regs.r10, &mut buf);
+
+    task::with_current(|task| task.write_user(regs.rsi, &buf[..len]))?;
+    regs.rdx = next;
+
+    Some(len as u64)
+}
+
+// klog(log, buf, len, seq), copies the kernel log from byte `seq` since boot
+// on, given a handle to the log with the read right. Returns how much was
+// copied and, in rdx, the `seq` to continue from. Text that has been
+// overwritten already is skipped
+pub fn sys_klog(regs: &mut Registers) {
+    regs.rax = klog(regs).unwrap_or(ERROR);
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use core::alloc::Layout;
+
+    use log::LevelFilter;
+
+    use super::{level_for, LogBuffer, DEFAULT_LEVEL, LOG_BUFFER_SIZE};
+
+    const SIZE: u64 = LOG_BUFFER_SIZE as u64;
+
+    // Too big for the stack, and all zeroes is an empty buffer
+    fn empty() -> Box<LogBuffer> {
+        unsafe {
+            Box::from_raw(alloc::alloc::alloc_zeroed(Layout::new::<LogBuffer>()) as *mut LogBuffer)
+        }
+    }
+
+    #[test_case]
+    fn read_continues_where_it_left_off() {
+        let mut log = empty();
+        log.push(b"hello world");
+
+        let mut buf = [0; 5];
+        assert_eq!(log.read(0, &mut buf), (5, 5));
+        assert_eq!(&buf, b"hello");
+
+        let mut buf = [0; 16];
+        assert_eq!(log.read(5, &mut buf), (6, 11));
+        assert_eq!(&buf[..6], b" world");
+    }
+
+    #[test_case]
+    fn read_wraps_around() {
+        let mut log = empty();
+        log.push(&alloc::vec![b'a'; LOG_BUFFER_SIZE - 2]);
+        log.push(b"wxyz");
+
+        let mut buf = [0; 8];
+        assert_eq!(log.read(SIZE - 4, &mut buf), (6, SIZE + 2));
+        assert_eq!(&buf[..6], b"aawxyz");
+    }
+
+    #[test_case]
+    fn read_skips_what_was_overwritten() {
+        let mut log = empty();
+        log.push(&alloc::vec![b'a'; LOG_BUFFER_SIZE]);
+        log.push(b"bc");
+
+        // Bytes 0 and 1 are gone, the oldest left is 2
+        let mut buf = [0; 3];
+        assert_eq!(log.read(0, &mut buf), (3, 5));
+        assert_eq!(&buf, b"aaa");
+
+        let mut buf = [0; 2];
+        assert_eq!(log.read(SIZE, &mut buf), (2, SIZE + 2));
+        assert_eq!(&buf, b"bc");
+    }
+
+    #[test_case]
+    fn read_past_the_end_gives_nothing() {
+        let mut log = empty();
+        log.push(b"abc");
+
+        let mut buf = [0; 4];
+        assert_eq!(log.read(3, &mut buf), (0, 3));
+        assert_eq!(log.read(100, &mut buf), (0, 3));
+    }
+
+    #[test_case]
+    fn level_for_takes_the_longest_match() {
+        let directives = "warn,kernel=info,kernel::sched=debug";
+
+        assert_eq!(level_for(directives, "kernel::sched"), LevelFilter::Debug);
+        assert_eq!(
+            level_for(directives, "kernel::sched::steal"),
+            LevelFilter::Debug
+        );
+        assert_eq!(level_for(directives, "kernel::mm"), LevelFilter::Info);
+        assert_eq!(level_for(directives, "kernel"), LevelFilter::Info);
+        assert_eq!(level_for(directives, "other"), LevelFilter::Warn);
+    }
+
+    #[test_case]
+    fn level_for_matches_whole_path_segments() {
+        let directives = "kernel::sched=trace";
+
+        assert_eq!(level_for(directives, "kernel::schedx"), DEFAULT_LEVEL);
+        assert_eq!(level_for(directives, "kernel::sch"), DEFAULT_LEVEL);
+        assert_eq!(
+            level_for(directives, "kernel::sched::x"),
+            LevelFilter::Trace
+        );
+    }
+
+    #[test_case]
+    fn level_for_ignores_bad_directives() {
+        assert_eq!(level_for("", "kernel"), DEFAULT_LEVEL);
+        assert_eq!(level_for("loud,kernel=nope", "kernel"), DEFAULT_LEVEL);
+        assert_eq!(level_for(",error,", "kernel"), LevelFilter::Error);
+    }
 }
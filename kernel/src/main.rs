@@ -1,40 +1,74 @@
 #![no_std]
 #![no_main]
-#![feature(panic_info_message, naked_functions, asm_sym, alloc_error_handler)]
+#![feature(
+    panic_info_message,
+    naked_functions,
+    asm_sym,
+    alloc_error_handler,
+    custom_test_frameworks
+)]
+#![test_runner(crate::testing::run)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
+mod acpi;
+mod apic;
+mod backtrace;
+mod cmdline;
 #[macro_use]
 mod core_locals;
 mod cpu;
+mod exceptions;
+mod futex;
+mod gdb;
+mod handle;
 mod interrupts;
+mod ipc;
+mod irq;
+#[cfg(feature = "lock_debug")]
+mod lockdep;
 mod logging;
 mod mm;
+mod monitor;
 mod paging;
 mod panic;
+mod pic;
+mod power;
 mod rangeset;
+mod sched;
 mod serial;
+mod shm;
+mod smp;
 mod sync;
+mod syscall;
 mod task;
+#[cfg(test)]
+mod testing;
+mod time;
+mod tlb;
 
 use crate::{
-    interrupts::{InterruptFrame, Interrupts},
+    interrupts::Interrupts,
     mm::{PhysAddr, VirtAddr},
     paging::{PageTable, PAGE_NX, PAGE_PRESENT, PAGE_WRITE},
-    task::Context,
 };
-use interrupts::Registers;
+use alloc::boxed::Box;
 use logging::Logger;
 use mm::PhysMem;
 use stivale_boot::v2::{
-    StivaleFramebufferHeaderTag, StivaleHeader, StivalePmrPermissionFlags, StivaleStruct,
+    StivaleFramebufferHeaderTag, StivaleHeader, StivalePmrPermissionFlags, StivaleSmpHeaderTag,
+    StivaleStruct,
 };
 use task::Task;
 
 pub static STACK: [u8; 32 * 1024] = [0; 32 * 1024];
 
-static FRAMEBUFFER_TAG: StivaleFramebufferHeaderTag =
-    StivaleFramebufferHeaderTag::new().framebuffer_bpp(24);
+static SMP_TAG: StivaleSmpHeaderTag = StivaleSmpHeaderTag::new();
+
+static FRAMEBUFFER_TAG: StivaleFramebufferHeaderTag = StivaleFramebufferHeaderTag::new()
+    .framebuffer_bpp(24)
+    .next((&SMP_TAG as *const StivaleSmpHeaderTag).cast());
 
 static LOGGER: Logger = Logger;
 
@@ -116,13 +150,30 @@ pub fn new_kernel_pagetable(
         }
     }
 
+    // Share the kernel stacks (IST stacks included) with the kernel page table
+    if let Some(kernel_page_table) = paging::KERNEL_PAGE_TABLE.lock().as_ref() {
+        unsafe {
+            page_table
+                .share_pml4_entry(allocator, kernel_page_table, mm::KERNEL_STACKS_BASE)
+                .unwrap();
+        }
+    }
+
     page_table
 }
 
 #[no_mangle]
 extern "C" fn _start(boot_info: &'static StivaleStruct) -> ! {
     log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(log::LevelFilter::Info);
+    // The `log` crate starts out dropping everything, use our defaults until
+    // the command line has been parsed
+    log::set_max_level(logging::DEFAULT_LEVEL);
+    cmdline::init(boot_info);
+    logging::init();
+
+    if backtrace::init(boot_info).is_none() {
+        log::warn!("No kernel symbols, stack traces will only have addresses");
+    }
 
     mm::init(boot_info).unwrap();
 
@@ -132,152 +183,59 @@ extern "C" fn _start(boot_info: &'static StivaleStruct) -> ! {
 
     unsafe { page_table.switch_to() }
 
+    log::debug!("{:#?}", page_table);
+    paging::set_kernel_page_table(page_table);
+
     {
-        let mut kernel_page_table = core!().kernel_page_table.lock();
-        *kernel_page_table = Some(page_table);
+        let mut interrupts = core!().interrupt_state.lock();
+        *interrupts = Some(Interrupts::init(&mut mm::PhysicalMemory));
     }
 
-    {
-        let kernel_page_table = core!().kernel_page_table.lock();
-        log::debug!("{:#?}", kernel_page_table.as_ref());
+    acpi::init(boot_info).expect("Failed to parse the ACPI tables");
+    irq::init().expect("Failed to set up interrupt routing");
+
+    if serial::init().is_none() {
+        log::warn!("No serial port found, console input is unavailable");
     }
 
-    {
-        let mut interrupts = core!().interrupt_state.lock();
-        *interrupts = Some(Interrupts::init(&mut mm::PhysicalMemory));
+    if gdb::init().is_none() {
+        log::info!("No second serial port, the GDB stub is disabled");
     }
 
-    let user_page_table = new_kernel_pagetable(&mut mm::PhysicalMemory, boot_info);
-    let user_task = mm::PhysicalMemory.alloc().unwrap();
-    *user_task = task::Task::new(&mut mm::PhysicalMemory, user_page_table).unwrap();
+    tlb::init().expect("Failed to set up TLB shootdowns");
+    sched::init().expect("Failed to set up the scheduler");
+    time::init().expect("Failed to set up the clocks");
 
-    let user_page_table = new_kernel_pagetable(&mut mm::PhysicalMemory, boot_info);
-    let other_task = mm::PhysicalMemory.alloc().unwrap();
-    *other_task = Task::new(&mut mm::PhysicalMemory, user_page_table).unwrap();
+    if smp::init(boot_info).is_none() {
+        log::warn!("No SMP information from the bootloader, running on the BSP only");
+    }
 
-    let user_page_table = new_kernel_pagetable(&mut mm::PhysicalMemory, boot_info);
-    let a_task = mm::PhysicalMemory.alloc().unwrap();
-    *a_task = Task::new(&mut mm::PhysicalMemory, user_page_table).unwrap();
+    // Never comes back, the runner exits QEMU
+    #[cfg(test)]
+    test_main();
 
     let mut init = None;
 
+    let init_path = cmdline::get().init;
     let modules = boot_info.modules().unwrap();
     for module in modules.iter() {
-        if module.as_str() == "__INIT__" {
+        if module.as_str() == init_path {
             init = Some(unsafe {
                 core::slice::from_raw_parts_mut(module.start as *mut u8, module.size() as usize)
             });
         }
     }
 
-    let init = init.unwrap();
-
-    user_task.load_elf(&mut mm::PhysicalMemory, init).unwrap();
-    other_task.load_elf(&mut mm::PhysicalMemory, init).unwrap();
-    a_task.load_elf(&mut mm::PhysicalMemory, init).unwrap();
-
-    // TODO: Use the right thing
-    unsafe {
-        core::arch::asm!(r#"
-        pushfq
-        pop {rflags}
+    let init = init.unwrap_or_else(|| panic!("No `{}` module to start", init_path));
 
-        or {rflags}, 1 << 12
-        or {rflags}, 1 << 13
+    // Three copies, spread over the cores
+    for _ in 0..3 {
+        let page_table = new_kernel_pagetable(&mut mm::PhysicalMemory, boot_info);
+        let mut task = Box::new(Task::new(&mut mm::PhysicalMemory, page_table).unwrap());
 
-        push {rflags}
-        popf
-
-        sti
-        "#, rflags = lateout(reg) _)
-    };
-
-    user_task.run()
-}
-
-pub extern "C" fn timer_int(frame: &InterruptFrame, regs: &Registers) {
-    if frame.cs & 0x3 == 0x3 {
-        unsafe { core::arch::asm!("swapgs") };
-    }
-
-    unsafe {
-        let kernel_page_table = core!().kernel_page_table.lock();
-        let kernel_page_table = kernel_page_table.as_ref().unwrap();
-        kernel_page_table.switch_to();
+        task.load_elf(&mut mm::PhysicalMemory, init).unwrap();
+        sched::enqueue(task);
     }
 
-    //log::info!("SYSCALL: \n{:#x?}", regs);
-    //log::info!("GS: {:#x}", unsafe { cpu::rdmsr(cpu::IA32_GS_BASE) });
-
-    {
-        let mut tasks = core!().tasks.lock();
-        let id = *core!().current_task_id.lock();
-
-        {
-            let task = &mut tasks[id];
-
-            task.save_context(Context {
-                regs: *regs,
-                rip: frame.rip as usize,
-                rsp: frame.rsp as usize,
-            });
-        }
-
-        if let Some(next) = tasks.get(id) {
-            log::info!("Switching to task {}!", id);
-            let page_table = unsafe { next.page_table() };
-            unsafe { (&*page_table).switch_to() };
-
-            unsafe { tasks.release_lock() };
-
-            if id + 1 != tasks.len() {
-                *core!().current_task_id.lock() = id + 1;
-            } else {
-                *core!().current_task_id.lock() = 0;
-            }
-
-            next.run()
-        } else {
-            let task = &mut tasks[id];
-            let page_table = unsafe { task.page_table() };
-            unsafe { (&*page_table).switch_to() }
-        }
-    }
-
-    if frame.cs & 0x3 == 0x3 {
-        unsafe { core::arch::asm!("swapgs") };
-    }
-}
-
-pub extern "C" fn int80(frame: &InterruptFrame, regs: &mut Registers) {
-    if frame.cs & 0x3 == 0x3 {
-        unsafe { core::arch::asm!("swapgs") };
-    }
-
-    unsafe {
-        let kernel_page_table = core!().kernel_page_table.lock();
-        let kernel_page_table = kernel_page_table.as_ref().unwrap();
-        kernel_page_table.switch_to();
-    }
-
-    log::info!("SYSCALL: \n{:#x?}", regs);
-    log::info!("GS: {:#x}", unsafe { cpu::rdmsr(cpu::IA32_GS_BASE) });
-
-    match regs.rax {
-        x => {
-            log::info!("Unknown syscall: {:#x}", x);
-            regs.rax = !0;
-        }
-    }
-
-    {
-        let tasks = core!().tasks.lock();
-        let task = &tasks[*core!().current_task_id.lock()];
-        let page_table = unsafe { task.page_table() };
-        unsafe { (&*page_table).switch_to() };
-    }
-
-    if frame.cs & 0x3 == 0x3 {
-        unsafe { core::arch::asm!("swapgs") };
-    }
+    sched::idle()
 }
@@ -1,11 +1,14 @@
 use core::alloc::GlobalAlloc;
 use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+use alloc::vec::Vec;
 use stivale_boot::v2::{StivaleMemoryMapEntryType, StivaleStruct};
 
 use crate::{
+    paging::{self, PageType, PAGE_NX, PAGE_PRESENT, PAGE_WRITE},
     rangeset::{Range, RangeSet},
-    sync::LockCell,
+    sync::IrqLockCell,
 };
 
 #[repr(transparent)]
@@ -21,6 +24,8 @@ pub trait PhysMem {
 
     fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr>;
 
+    fn free_phys(&mut self, phys: PhysAddr, size: usize);
+
     fn alloc_phys_zeroed(&mut self, layout: Layout) -> Option<PhysAddr> {
         let alc = self.alloc_phys(layout)?;
 
@@ -35,18 +40,6 @@ pub trait PhysMem {
 
 pub struct PhysicalMemory;
 
-impl PhysicalMemory {
-    pub fn alloc<T>(&mut self) -> Option<&'static mut T> {
-        let addr = self.alloc_phys_zeroed(
-            Layout::from_size_align(core::mem::size_of::<T>(), core::mem::align_of::<T>()).ok()?,
-        )?;
-
-        let virt = unsafe { self.translate(addr, core::mem::size_of::<T>()) }?;
-
-        unsafe { Some(&mut *(virt as *mut T)) }
-    }
-}
-
 impl PhysMem for PhysicalMemory {
     unsafe fn translate(&mut self, phys: PhysAddr, size: usize) -> Option<*mut u8> {
         if size == 0 {
@@ -66,9 +59,92 @@ impl PhysMem for PhysicalMemory {
             })
             .unwrap_or(None)
     }
+
+    fn free_phys(&mut self, phys: PhysAddr, size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        let mut phys_mem = ALLOCATOR.lock();
+        if let Some(alloc) = phys_mem.as_mut() {
+            alloc.insert(Range {
+                start: phys.0 as u64,
+                end: (phys.0 + size - 1) as u64,
+            });
+        }
+    }
+}
+
+// Kernel stacks live in their own PML4 slot so every page table can share them
+pub const KERNEL_STACKS_BASE: VirtAddr = VirtAddr(0xffffff0000000000);
+
+static NEXT_KERNEL_STACK: AtomicUsize = AtomicUsize::new(KERNEL_STACKS_BASE.0);
+
+// Maps a new kernel stack of `size` bytes with an unmapped guard page below it
+// into the kernel page table, returning the top of the stack
+pub fn alloc_kernel_stack(phys_mem: &mut dyn PhysMem, size: usize) -> Option<VirtAddr> {
+    let mut page_table = paging::KERNEL_PAGE_TABLE.lock();
+    let page_table = page_table.as_mut()?;
+
+    let pages = (size + 0xfff) / 0x1000;
+
+    // Skip over the guard page
+    let base = NEXT_KERNEL_STACK.fetch_add((pages + 1) * 4096, Ordering::SeqCst) + 4096;
+
+    for page in 0..pages {
+        let phys = phys_mem.alloc_phys_zeroed(Layout::from_size_align(4096, 4096).ok()?)?;
+
+        unsafe {
+            page_table.map_raw(
+                phys_mem,
+                VirtAddr(base + page * 4096),
+                PageType::Page4K,
+                phys.0 | PAGE_NX | PAGE_WRITE | PAGE_PRESENT,
+                true,
+                false,
+                false,
+            )?;
+        }
+    }
+
+    Some(VirtAddr(base + pages * 4096))
+}
+
+// Every thread enters the kernel on a stack of its own of this size
+pub const THREAD_STACK_SIZE: usize = 32 * 1024;
+
+// Stacks of threads that are gone, still mapped and waiting for the next one.
+// They are never unmapped, so no core can be left with a stale translation
+static FREE_THREAD_STACKS: IrqLockCell<Vec<VirtAddr>> = IrqLockCell::new(Vec::new());
+
+// The kernel stack of one thread, handed on to a new thread once it is dropped
+pub struct KernelStack {
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    pub fn new() -> Option<KernelStack> {
+        let free = FREE_THREAD_STACKS.lock().pop();
+        let top = match free {
+            Some(top) => top,
+            None => alloc_kernel_stack(&mut PhysicalMemory, THREAD_STACK_SIZE)?,
+        };
+
+        Some(KernelStack { top })
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        FREE_THREAD_STACKS.lock().push(self.top);
+    }
 }
 
-pub static ALLOCATOR: LockCell<Option<RangeSet>> = LockCell::new(None);
+pub static ALLOCATOR: IrqLockCell<Option<RangeSet>> = IrqLockCell::new(None);
 
 #[global_allocator]
 static GLOBAL_ALLOCATOR: GlobalAllocator = GlobalAllocator;
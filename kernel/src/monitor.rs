@@ -0,0 +1,305 @@
+use alloc::sync::Arc;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+use crate::{
+    backtrace, core_locals, mm,
+    mm::{PhysAddr, PhysMem, VirtAddr},
+    paging::{self, PageTable, PAGE_NX, PAGE_USER, PAGE_WRITE},
+    sched, serial,
+    task::Process,
+    tlb,
+};
+
+// Ctrl-A followed by this enters the monitor
+pub const MAGIC_PREFIX: u8 = 0x01;
+pub const MAGIC_KEY: u8 = b'm';
+
+const MAX_LINE: usize = 128;
+const MAX_DUMP: usize = 4096;
+
+// Physical memory is only reachable through the identity map of the low 4GiB
+const IDENTITY_END: usize = 1 << 32;
+
+const HELP: &str = "\
+help                      this text
+tasks                     runnable tasks on every core
+cores                     per core state
+pt <task>                 user mappings of a task
+free                      free physical memory
+phys <addr> [len]         dump physical memory
+virt <addr> [len] [task]  dump virtual memory, the kernel's by default
+bt                        backtrace of the monitor itself
+exit                      back to running
+";
+
+macro_rules! out {
+    ($($arg:tt)*) => {{
+        let _ = write!(serial::lock(), $($arg)*);
+    }};
+}
+
+fn parse(arg: &str) -> Option<usize> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+// Blocks for the next byte. The core has interrupts off in here, so keep
+// answering shootdowns or whoever sent one waits until we leave
+fn getc() -> u8 {
+    loop {
+        if let Some(byte) = serial::COM1.poll() {
+            return byte;
+        }
+
+        tlb::service_pending();
+        core::hint::spin_loop();
+    }
+}
+
+fn read_line(buf: &mut [u8; MAX_LINE]) -> &str {
+    let mut len = 0;
+
+    loop {
+        match getc() {
+            b'\r' | b'\n' => {
+                out!("\n");
+                break;
+            }
+            // Backspace and delete
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                out!("\x08 \x08");
+            }
+            byte @ 0x20..=0x7e if len < MAX_LINE => {
+                buf[len] = byte;
+                len += 1;
+                out!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+fn hexdump(addr: usize, data: &[u8]) {
+    let mut serial = serial::lock();
+
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let _ = write!(serial, "{:016x}:", addr + line * 16);
+        for byte in chunk {
+            let _ = write!(serial, " {:02x}", byte);
+        }
+
+        let _ = write!(serial, "{:width$}  ", "", width = (16 - chunk.len()) * 3);
+        for &byte in chunk {
+            let c = if (0x20..0x7f).contains(&byte) {
+                byte as char
+            } else {
+                '.'
+            };
+            let _ = write!(serial, "{}", c);
+        }
+        let _ = writeln!(serial);
+    }
+}
+
+// Process of a task that is on some run queue. Holding it keeps its page
+// table around after the task exits
+fn task_process(id: usize) -> Option<Arc<Process>> {
+    core_locals::cores().find_map(|core| {
+        core.tasks
+            .lock()
+            .iter()
+            .find(|task| task.id() == id)
+            .map(|task| task.process())
+    })
+}
+
+fn tasks() {
+    // Blocked tasks sit with whatever they wait on, not on a queue
+    for core in core_locals::cores() {
+        let tasks = core.tasks.lock();
+        let current = *core.current_task_id.lock();
+
+        for (index, task) in tasks.iter().enumerate() {
+            out!(
+                "core {:<2} {} task {:<4} rip {:#018x} rsp {:#018x} affinity {:#x} cr3 {:#x}\n",
+                core.id,
+                if index == current { '*' } else { ' ' },
+                task.id(),
+                task.context().rip,
+                task.context().rsp,
+                task.affinity(),
+                task.page_table().0
+            );
+        }
+    }
+}
+
+fn cores() {
+    for core in core_locals::cores() {
+        let stats = sched::utilization(core);
+
+        out!(
+            "core {:<2} apic {:<3} cr3 {:#x} current {} queued {} idle {} busy {}.{}% switches {} steals {}\n",
+            core.id,
+            core.apic_id,
+            core.active_page_table.load(Ordering::SeqCst),
+            *core.current_task_id.lock(),
+            stats.queued,
+            core.idle.load(Ordering::SeqCst),
+            stats.busy_permille() / 10,
+            stats.busy_permille() % 10,
+            stats.switches,
+            stats.steals
+        );
+    }
+}
+
+fn page_table(id: usize) {
+    let Some(process) = task_process(id) else {
+        out!("no runnable task {}\n", id);
+        return;
+    };
+
+    // We may have interrupted whoever holds it, so do not wait for it
+    let Some(page_table) = process.try_page_table() else {
+        out!("page table of task {} is busy\n", id);
+        return;
+    };
+
+    page_table.walk_user(&mut mm::PhysicalMemory, |vaddr, ent, page_type| {
+        out!(
+            "{:#018x} -> {:#014x} {:>4}K {}{}{}\n",
+            vaddr.0,
+            ent & 0xffffffffff000,
+            page_type as usize / 1024,
+            if ent & PAGE_WRITE != 0 { 'w' } else { '-' },
+            if ent & PAGE_USER != 0 { 'u' } else { '-' },
+            if ent & PAGE_NX != 0 { '-' } else { 'x' }
+        );
+    });
+}
+
+fn free() {
+    // Copied out so the allocator is not held while printing
+    let Some(free) = *mm::ALLOCATOR.lock() else {
+        return;
+    };
+
+    for range in free.entries() {
+        out!(
+            "{:#014x}-{:#014x} {} KiB\n",
+            range.start,
+            range.end,
+            (range.end - range.start + 1) / 1024
+        );
+    }
+
+    out!("{} KiB free\n", free.sum().unwrap_or(0) / 1024);
+}
+
+fn phys(addr: usize, len: usize) {
+    let mut buf = [0u8; MAX_DUMP];
+    let buf = &mut buf[..len.min(MAX_DUMP)];
+
+    if !matches!(addr.checked_add(buf.len()), Some(end) if end <= IDENTITY_END) {
+        out!("{:#x} is outside the identity map\n", addr);
+        return;
+    }
+
+    match unsafe { mm::PhysicalMemory.translate(PhysAddr(addr), buf.len()) } {
+        Some(ptr) => {
+            unsafe { core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) };
+            hexdump(addr, buf);
+        }
+        None => out!("cannot access {:#x}\n", addr),
+    }
+}
+
+fn virt(addr: usize, len: usize, task: Option<usize>) {
+    let mut buf = [0u8; MAX_DUMP];
+    let buf = &mut buf[..len.min(MAX_DUMP)];
+
+    let read = match task {
+        Some(id) => {
+            let Some(process) = task_process(id) else {
+                out!("no runnable task {}\n", id);
+                return;
+            };
+            let Some(page_table) = process.try_page_table() else {
+                out!("page table of task {} is busy\n", id);
+                return;
+            };
+
+            page_table.read_virt(&mut mm::PhysicalMemory, VirtAddr(addr), buf)
+        }
+        None => {
+            let Some(root) = paging::kernel_table() else {
+                out!("no kernel page table\n");
+                return;
+            };
+
+            // Never freed
+            let page_table = unsafe { PageTable::from_table(root) };
+            page_table.read_virt(&mut mm::PhysicalMemory, VirtAddr(addr), buf)
+        }
+    };
+
+    match read {
+        Some(()) => hexdump(addr, buf),
+        None => out!("{:#x} is not mapped\n", addr),
+    }
+}
+
+fn backtrace() {
+    let _ = backtrace::write_current(&mut serial::lock());
+}
+
+// Runs the monitor on this core until `exit`. Called from the monitor thread
+// with interrupts off, the other cores keep going meanwhile
+pub fn run() {
+    out!(
+        "\nIcecube monitor on core {}, `help` lists commands\n",
+        core!().id
+    );
+
+    let mut line = [0u8; MAX_LINE];
+    loop {
+        out!("> ");
+
+        let mut args = read_line(&mut line).split_whitespace();
+        let command = args.next();
+        let mut arg = || args.next().and_then(parse);
+
+        match command {
+            None => {}
+            Some("help") => out!("{}", HELP),
+            Some("tasks") => tasks(),
+            Some("cores") => cores(),
+            Some("pt") => match arg() {
+                Some(id) => page_table(id),
+                None => out!("usage: pt <task>\n"),
+            },
+            Some("free") => free(),
+            Some("phys") => match arg() {
+                Some(addr) => phys(addr, arg().unwrap_or(64)),
+                None => out!("usage: phys <addr> [len]\n"),
+            },
+            Some("virt") => match arg() {
+                Some(addr) => {
+                    let len = arg().unwrap_or(64);
+                    virt(addr, len, arg());
+                }
+                None => out!("usage: virt <addr> [len] [task]\n"),
+            },
+            Some("bt") => backtrace(),
+            Some("exit") => break,
+            Some(other) => out!("unknown command `{}`\n", other),
+        }
+    }
+}
@@ -1,13 +1,19 @@
 use core::alloc::Layout;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::mm::{PhysAddr, PhysMem, VirtAddr};
+use crate::sync::IrqLockCell;
 
 pub const PAGE_PRESENT: usize = 1 << 0;
 pub const PAGE_WRITE: usize = 1 << 1;
 pub const PAGE_USER: usize = 1 << 2;
+pub const PAGE_HUGE: usize = 1 << 7;
 pub const PAGE_NX: usize = 1 << 63;
 
+// User addresses are all below this
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
 #[repr(usize)]
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
@@ -22,14 +28,274 @@ pub struct PageTable {
     table: PhysAddr,
 }
 
+// The page table the kernel runs on. Every core shares this one, so changes to
+// the kernel half are serialized whichever core makes them
+pub static KERNEL_PAGE_TABLE: IrqLockCell<Option<PageTable>> = IrqLockCell::new(None);
+
+// Root of `KERNEL_PAGE_TABLE`, for switching to it without the lock. Entry
+// paths do that with interrupts off, where spinning on the lock could wait on
+// a core that waits on us for a TLB shootdown
+static KERNEL_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+// Must run on the BSP before any other core starts
+pub fn set_kernel_page_table(page_table: PageTable) {
+    KERNEL_TABLE.store(page_table.table.0, Ordering::SeqCst);
+    *KERNEL_PAGE_TABLE.lock() = Some(page_table);
+}
+
+pub fn kernel_table() -> Option<PhysAddr> {
+    match KERNEL_TABLE.load(Ordering::SeqCst) {
+        0 => None,
+        table => Some(PhysAddr(table)),
+    }
+}
+
+// Switches this core to the kernel page table
+pub unsafe fn load_kernel() {
+    load(kernel_table().expect("No kernel page table yet"));
+}
+
+// Switches this core to the page table rooted at `table`
+pub unsafe fn load(table: PhysAddr) {
+    crate::cpu::set_cr3(table.0);
+
+    // Lets TLB shootdowns know which cores need to hear about changes
+    core!().active_page_table.store(table.0, Ordering::SeqCst);
+}
+
 impl PageTable {
     pub fn new(pmem: &mut dyn PhysMem) -> Option<PageTable> {
         let table = pmem.alloc_phys_zeroed(Layout::from_size_align(4096, 4096).ok()?)?;
         Some(PageTable { table })
     }
 
+    // Wraps an existing top level table, e.g. the one currently in CR3
+    pub unsafe fn from_table(table: PhysAddr) -> PageTable {
+        PageTable {
+            table: PhysAddr(table.0 & 0xffffffffff000),
+        }
+    }
+
+    pub fn table(&self) -> PhysAddr {
+        self.table
+    }
+
+    pub fn translate(&self, phys_mem: &mut dyn PhysMem, vaddr: VirtAddr) -> Option<PhysAddr> {
+        self.lookup(phys_mem, vaddr).map(|(phys, _)| phys)
+    }
+
+    // Like `translate`, but also hands back the final level entry
+    fn lookup(&self, phys_mem: &mut dyn PhysMem, vaddr: VirtAddr) -> Option<(PhysAddr, usize)> {
+        let indicies = [
+            (vaddr.0 >> 39) & 0x1ff,
+            (vaddr.0 >> 30) & 0x1ff,
+            (vaddr.0 >> 21) & 0x1ff,
+            (vaddr.0 >> 12) & 0x1ff,
+        ];
+
+        let mut table = self.table;
+        for (depth, &index) in indicies.iter().enumerate() {
+            let ptp = PhysAddr(table.0 + index * size_of::<usize>());
+            let ent = unsafe { *(phys_mem.translate(ptp, size_of::<usize>())? as *const usize) };
+
+            if (ent & PAGE_PRESENT) == 0 {
+                return None;
+            }
+
+            let mask = match depth {
+                1 if (ent & PAGE_HUGE) != 0 => PageType::Page1G as usize - 1,
+                2 if (ent & PAGE_HUGE) != 0 => PageType::Page2M as usize - 1,
+                3 => PageType::Page4K as usize - 1,
+                _ => {
+                    table = PhysAddr(ent & 0xffffffffff000);
+                    continue;
+                }
+            };
+
+            return Some((
+                PhysAddr((ent & 0xffffffffff000 & !mask) | (vaddr.0 & mask)),
+                ent,
+            ));
+        }
+
+        unreachable!();
+    }
+
+    // Copies memory out of this address space without switching to it, fails
+    // if any of the bytes are not mapped
+    pub fn read_virt(
+        &self,
+        phys_mem: &mut dyn PhysMem,
+        vaddr: VirtAddr,
+        buf: &mut [u8],
+    ) -> Option<()> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let phys = self.translate(phys_mem, VirtAddr(vaddr.0.checked_add(i)?))?;
+            *byte = unsafe { *phys_mem.translate(phys, 1)? };
+        }
+
+        Some(())
+    }
+
+    // Copies memory into this address space, ignoring the page permissions
+    // like a debugger would. Fails at the first byte that is not mapped
+    pub fn write_virt(
+        &self,
+        phys_mem: &mut dyn PhysMem,
+        vaddr: VirtAddr,
+        data: &[u8],
+    ) -> Option<()> {
+        for (i, &byte) in data.iter().enumerate() {
+            let phys = self.translate(phys_mem, VirtAddr(vaddr.0.checked_add(i)?))?;
+            unsafe { *phys_mem.translate(phys, 1)? = byte };
+        }
+
+        Some(())
+    }
+
+    // Runs `f` on each physically contiguous piece of `len` bytes at `vaddr`,
+    // failing on anything user mode could not access itself
+    fn user_chunks(
+        &self,
+        phys_mem: &mut dyn PhysMem,
+        vaddr: VirtAddr,
+        len: usize,
+        write: bool,
+        mut f: impl FnMut(*mut u8, usize, usize),
+    ) -> Option<()> {
+        let end = vaddr.0.checked_add(len)?;
+        if end > USER_END {
+            return None;
+        }
+
+        let mut addr = vaddr.0;
+        while addr < end {
+            let (phys, ent) = self.lookup(phys_mem, VirtAddr(addr))?;
+            if ent & PAGE_USER == 0 || (write && ent & PAGE_WRITE == 0) {
+                return None;
+            }
+
+            let chunk = (0x1000 - (addr & 0xfff)).min(end - addr);
+            let ptr = unsafe { phys_mem.translate(phys, chunk)? };
+            f(ptr, addr - vaddr.0, chunk);
+
+            addr += chunk;
+        }
+
+        Some(())
+    }
+
+    pub fn read_user(
+        &self,
+        phys_mem: &mut dyn PhysMem,
+        vaddr: VirtAddr,
+        buf: &mut [u8],
+    ) -> Option<()> {
+        self.user_chunks(
+            phys_mem,
+            vaddr,
+            buf.len(),
+            false,
+            |ptr, offset, len| unsafe {
+                core::ptr::copy_nonoverlapping(ptr, buf[offset..].as_mut_ptr(), len)
+            },
+        )
+    }
+
+    pub fn write_user(
+        &self,
+        phys_mem: &mut dyn PhysMem,
+        vaddr: VirtAddr,
+        data: &[u8],
+    ) -> Option<()> {
+        self.user_chunks(
+            phys_mem,
+            vaddr,
+            data.len(),
+            true,
+            |ptr, offset, len| unsafe {
+                core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), ptr, len)
+            },
+        )
+    }
+
+    // Calls `f` with the address, final level entry and size of every page
+    // mapped in the user half
+    pub fn walk_user(
+        &self,
+        phys_mem: &mut dyn PhysMem,
+        mut f: impl FnMut(VirtAddr, usize, PageType),
+    ) {
+        self.walk_level(phys_mem, self.table, 0, 0, &mut f);
+    }
+
+    fn walk_level(
+        &self,
+        phys_mem: &mut dyn PhysMem,
+        table: PhysAddr,
+        depth: usize,
+        base: usize,
+        f: &mut dyn FnMut(VirtAddr, usize, PageType),
+    ) {
+        // Only the lower half of the top level belongs to user mode
+        let entries = if depth == 0 { 256 } else { 512 };
+
+        for index in 0..entries {
+            let ptp = PhysAddr(table.0 + index * size_of::<usize>());
+            let ent = match unsafe { phys_mem.translate(ptp, size_of::<usize>()) } {
+                Some(ent) => unsafe { *(ent as *const usize) },
+                None => return,
+            };
+
+            if (ent & PAGE_PRESENT) == 0 {
+                continue;
+            }
+
+            let vaddr = VirtAddr(base | (index << (39 - depth * 9)));
+            match depth {
+                1 if (ent & PAGE_HUGE) != 0 => f(vaddr, ent, PageType::Page1G),
+                2 if (ent & PAGE_HUGE) != 0 => f(vaddr, ent, PageType::Page2M),
+                3 => f(vaddr, ent, PageType::Page4K),
+                _ => self.walk_level(
+                    phys_mem,
+                    PhysAddr(ent & 0xffffffffff000),
+                    depth + 1,
+                    vaddr.0,
+                    f,
+                ),
+            }
+        }
+    }
+
+    // Frees the tables of an address space nothing runs on any more, along
+    // with every page still mapped into it for user mode. Those are the only
+    // ones it owns, the identity map and the kernel are mapped without
+    // PAGE_USER. Top level entries that are the same as in the table at
+    // `shared` belong to that one and stay
+    pub unsafe fn free(&mut self, phys_mem: &mut dyn PhysMem, shared: PhysAddr) {
+        for index in 0..512 {
+            let mut entry = |table: PhysAddr| {
+                phys_mem
+                    .translate(
+                        PhysAddr(table.0 + index * size_of::<usize>()),
+                        size_of::<usize>(),
+                    )
+                    .map(|ent| *(ent as *const usize))
+            };
+
+            let ent = entry(self.table).unwrap_or(0);
+            if (ent & PAGE_PRESENT) == 0 || entry(shared) == Some(ent) {
+                continue;
+            }
+
+            free_level(phys_mem, PhysAddr(ent & 0xffffffffff000), 1);
+        }
+
+        phys_mem.free_phys(self.table, 4096);
+    }
+
     pub unsafe fn switch_to(&self) {
-        crate::cpu::set_cr3(self.table.0);
+        load(self.table);
     }
 
     pub unsafe fn read_to<T>(&self, addr: *const T, change: Option<&PageTable>) -> T {
@@ -70,6 +336,28 @@ impl PageTable {
         }
     }
 
+    pub unsafe fn share_pml4_entry(
+        &mut self,
+        phys_mem: &mut dyn PhysMem,
+        other: &PageTable,
+        vaddr: VirtAddr,
+    ) -> Option<()> {
+        let index = (vaddr.0 >> 39) & 0x1ff;
+
+        let src = phys_mem.translate(
+            PhysAddr(other.table.0 + index * size_of::<usize>()),
+            size_of::<usize>(),
+        )?;
+        let dst = phys_mem.translate(
+            PhysAddr(self.table.0 + index * size_of::<usize>()),
+            size_of::<usize>(),
+        )?;
+
+        *(dst as *mut usize) = *(src as *const usize);
+
+        Some(())
+    }
+
     pub unsafe fn map_raw(
         &mut self,
         phys_mem: &mut dyn PhysMem,
@@ -125,8 +413,8 @@ impl PageTable {
                 if (ent & PAGE_PRESENT) != 0 {
                     *(vad as *mut usize) = raw;
 
-                    if invlpg_on_update && size_of::<VirtAddr>() == size_of::<usize>() {
-                        crate::cpu::invlpg(vaddr.0);
+                    if invlpg_on_update {
+                        crate::tlb::shootdown(self.table, vaddr, page_type as usize);
                     }
                 } else {
                     *(vad as *mut usize) = raw;
@@ -142,4 +430,173 @@ impl PageTable {
 
         unreachable!();
     }
+
+    // Finds the final level entry mapping `vaddr` as a `page_type` page
+    unsafe fn leaf_entry(
+        &self,
+        phys_mem: &mut dyn PhysMem,
+        vaddr: VirtAddr,
+        page_type: PageType,
+    ) -> Option<*mut usize> {
+        let depth = match page_type {
+            PageType::Page4K => 4,
+            PageType::Page2M => 3,
+            PageType::Page1G => 2,
+        };
+
+        let mut table = self.table;
+        for level in 0..depth {
+            let index = (vaddr.0 >> (39 - level * 9)) & 0x1ff;
+            let vad = phys_mem.translate(
+                PhysAddr(table.0 + index * size_of::<usize>()),
+                size_of::<usize>(),
+            )? as *mut usize;
+
+            if level == depth - 1 {
+                return Some(vad);
+            }
+
+            let ent = *vad;
+            if (ent & PAGE_PRESENT) == 0 || (ent & PAGE_HUGE) != 0 {
+                return None;
+            }
+
+            table = PhysAddr(ent & 0xffffffffff000);
+        }
+
+        unreachable!();
+    }
+
+    // Removes the mapping of `vaddr` from every core, returning the old entry
+    pub unsafe fn unmap(
+        &mut self,
+        phys_mem: &mut dyn PhysMem,
+        vaddr: VirtAddr,
+        page_type: PageType,
+    ) -> Option<usize> {
+        let entry = self.leaf_entry(phys_mem, vaddr, page_type)?;
+
+        let old = *entry;
+        if (old & PAGE_PRESENT) == 0 {
+            return None;
+        }
+
+        *entry = 0;
+        crate::tlb::shootdown(self.table, vaddr, page_type as usize);
+
+        Some(old)
+    }
+
+    // Sets and clears permission bits on an existing mapping
+    pub unsafe fn protect(
+        &mut self,
+        phys_mem: &mut dyn PhysMem,
+        vaddr: VirtAddr,
+        page_type: PageType,
+        set: usize,
+        clear: usize,
+    ) -> Option<()> {
+        let entry = self.leaf_entry(phys_mem, vaddr, page_type)?;
+
+        if (*entry & PAGE_PRESENT) == 0 {
+            return None;
+        }
+
+        *entry = (*entry | set) & !clear;
+        crate::tlb::shootdown(self.table, vaddr, page_type as usize);
+
+        Some(())
+    }
+}
+
+// Frees a table below the top level and everything under it, leaves only when
+// they are mapped for user mode
+unsafe fn free_level(phys_mem: &mut dyn PhysMem, table: PhysAddr, depth: usize) {
+    for index in 0..512 {
+        let ent = match phys_mem.translate(
+            PhysAddr(table.0 + index * size_of::<usize>()),
+            size_of::<usize>(),
+        ) {
+            Some(ent) => *(ent as *const usize),
+            None => continue,
+        };
+
+        if (ent & PAGE_PRESENT) == 0 {
+            continue;
+        }
+
+        let frame = PhysAddr(ent & 0xffffffffff000);
+        let size = match depth {
+            1 if (ent & PAGE_HUGE) != 0 => PageType::Page1G,
+            2 if (ent & PAGE_HUGE) != 0 => PageType::Page2M,
+            3 => PageType::Page4K,
+            _ => {
+                free_level(phys_mem, frame, depth + 1);
+                continue;
+            }
+        };
+
+        if (ent & PAGE_USER) != 0 {
+            phys_mem.free_phys(frame, size as usize);
+        }
+    }
+
+    phys_mem.free_phys(table, 4096);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::Layout;
+
+    use super::{PageTable, PageType, PAGE_PRESENT, PAGE_USER, PAGE_WRITE};
+    use crate::mm::{PhysMem, PhysicalMemory, VirtAddr};
+
+    #[test_case]
+    fn mapped_pages_read_back_what_was_written() {
+        let layout = Layout::from_size_align(4096, 4096).unwrap();
+        let frame = PhysicalMemory.alloc_phys_zeroed(layout).unwrap();
+        let mut page_table = PageTable::new(&mut PhysicalMemory).unwrap();
+
+        let vaddr = VirtAddr(0x1234_5000);
+        unsafe {
+            page_table
+                .map_raw(
+                    &mut PhysicalMemory,
+                    vaddr,
+                    PageType::Page4K,
+                    frame.0 | PAGE_PRESENT | PAGE_WRITE | PAGE_USER,
+                    true,
+                    false,
+                    false,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            page_table.translate(&mut PhysicalMemory, vaddr),
+            Some(frame)
+        );
+        assert_eq!(
+            page_table.translate(&mut PhysicalMemory, VirtAddr(vaddr.0 + 4096)),
+            None
+        );
+
+        let data = *b"icecube";
+        page_table
+            .write_virt(&mut PhysicalMemory, VirtAddr(vaddr.0 + 100), &data)
+            .unwrap();
+
+        // Runs off the end of the only mapped page
+        assert!(page_table
+            .write_virt(&mut PhysicalMemory, VirtAddr(vaddr.0 + 4093), &data)
+            .is_none());
+
+        let mut buf = [0u8; 7];
+        page_table
+            .read_virt(&mut PhysicalMemory, VirtAddr(vaddr.0 + 100), &mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"icecube");
+
+        PhysicalMemory.free_phys(frame, 4096);
+    }
 }
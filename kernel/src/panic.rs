@@ -1,9 +1,53 @@
-use core::{fmt::Write, panic::PanicInfo};
+use core::{
+    fmt::Write,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use crate::serial::EmergencySerial;
+use crate::{
+    apic::{self, Ipi},
+    backtrace,
+    cmdline::{self, PanicAction},
+    core_locals, cpu,
+    power::{self, QemuExitCode},
+    serial::EmergencySerial,
+};
+
+// Set by the first core to panic, everyone else stops where they are
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::SeqCst)
+}
+
+// NMIs get through no matter what the other cores are doing, and the NMI
+// handler parks them once it sees we are panicking. Cores are matched on their
+// APIC ID as it works before our core locals exist
+fn stop_other_cores() {
+    let apic_id = cpu::cpuid(1, 0).1 >> 24;
+
+    for core in core_locals::cores() {
+        if core.apic_id != apic_id {
+            apic::send_ipi(core.apic_id, Ipi::Nmi);
+        }
+    }
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    unsafe { cpu::disable_interrupts() };
+
+    // Either another core got here first and will stop us, or we panicked
+    // while panicking
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        cpu::stop();
+    }
+
+    stop_other_cores();
+
+    #[cfg(test)]
+    crate::testing::fail();
+
     let mut serial = EmergencySerial;
 
     let _ = serial.write_fmt(format_args!("PANIC: \n"));
@@ -19,5 +63,15 @@ fn panic(info: &PanicInfo) -> ! {
         .message()
         .map(|message| serial.write_fmt(format_args!("{}\n", message)));
 
-    loop {}
+    let _ = backtrace::write_current(&mut serial);
+
+    log::logger().flush();
+
+    match cmdline::get().panic {
+        PanicAction::Halt => {}
+        PanicAction::Reboot => power::reboot(),
+        PanicAction::ExitQemu => power::exit_qemu(QemuExitCode::Failure),
+    }
+
+    cpu::stop()
 }
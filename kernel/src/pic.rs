@@ -0,0 +1,36 @@
+use crate::cpu::outb;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+// Where the legacy PIC vectors end up, out of the way of the CPU exceptions
+pub const PIC_VECTOR_BASE: u8 = 0x20;
+
+// Remaps the 8259 pair past the exception vectors and masks every line, all
+// external interrupts go through the IO-APIC instead. Remapping first matters as
+// a masked PIC can still raise a spurious IRQ 7 or 15.
+pub fn disable() {
+    unsafe {
+        // ICW1: start initialization, expect ICW4
+        outb(PIC1_COMMAND, 0x11);
+        outb(PIC2_COMMAND, 0x11);
+
+        // ICW2: vector offsets
+        outb(PIC1_DATA, PIC_VECTOR_BASE);
+        outb(PIC2_DATA, PIC_VECTOR_BASE + 8);
+
+        // ICW3: secondary PIC on IRQ 2
+        outb(PIC1_DATA, 1 << 2);
+        outb(PIC2_DATA, 2);
+
+        // ICW4: 8086 mode
+        outb(PIC1_DATA, 0x01);
+        outb(PIC2_DATA, 0x01);
+
+        // Mask everything
+        outb(PIC1_DATA, 0xff);
+        outb(PIC2_DATA, 0xff);
+    }
+}
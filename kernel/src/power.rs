@@ -0,0 +1,94 @@
+#![allow(dead_code)]
+
+use crate::{
+    acpi::{self, Fadt, GenericAddress},
+    cpu,
+    mm::{PhysAddr, PhysMem, PhysicalMemory},
+};
+
+// Where QEMU's isa-debug-exit device is expected, i.e.
+// `-device isa-debug-exit,iobase=0xf4,iosize=0x04`
+const QEMU_EXIT_PORT: u16 = 0xf4;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+// QEMU exits with `(code << 1) | 1`, so 33 for success and 35 for failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+// Only returns when not running under QEMU with the exit device
+pub fn exit_qemu(code: QemuExitCode) {
+    unsafe { cpu::outb(QEMU_EXIT_PORT, code as u8) };
+}
+
+// Writes the FADT reset value to the reset register, if the firmware has one
+fn acpi_reset() {
+    // Whoever held the lock may be stopped for good
+    let Some(fadt) = acpi::FADT.try_lock().and_then(|fadt| *fadt) else {
+        return;
+    };
+    let Some(reg) = fadt.reset_register else {
+        return;
+    };
+
+    unsafe {
+        match reg.address_space {
+            GenericAddress::SYSTEM_IO => cpu::outb(reg.address as u16, fadt.reset_value),
+            GenericAddress::SYSTEM_MEMORY => {
+                if let Some(ptr) = PhysicalMemory.translate(PhysAddr(reg.address as usize), 1) {
+                    core::ptr::write_volatile(ptr, fadt.reset_value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Pulses the reset line through the 8042, which most PCs still emulate
+fn keyboard_controller_reset() {
+    // No flags at all, e.g. in revision 1 tables, means nothing is said
+    let boot_arch = acpi::FADT
+        .try_lock()
+        .and_then(|fadt| *fadt)
+        .map_or(0, |fadt| fadt.iapc_boot_arch);
+    if boot_arch != 0 && boot_arch & Fadt::BOOT_ARCH_8042 == 0 {
+        return;
+    }
+
+    unsafe {
+        for _ in 0..0x10000 {
+            if cpu::inb(KBC_STATUS) & KBC_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        cpu::outb(KBC_COMMAND, KBC_PULSE_RESET);
+    }
+}
+
+// Tries the ways to reset the machine from the politest down, a triple fault
+// always works
+pub fn reboot() -> ! {
+    unsafe { cpu::disable_interrupts() };
+
+    acpi_reset();
+    keyboard_controller_reset();
+
+    // Give the reset a moment to happen before pulling the rug
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+
+    let idtr = [0u8; 10];
+    unsafe {
+        core::arch::asm!("lidt [{}]", "int3", in(reg) &idtr, options(noreturn));
+    }
+}
@@ -193,3 +193,42 @@ fn contains(mut x1: u64, mut x2: u64, mut y1: u64, mut y2: u64) -> bool {
 
     x1 >= y1 && x2 <= y2
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Range, RangeSet};
+
+    #[test_case]
+    fn insert_merges_touching_ranges() {
+        let mut rs = RangeSet::new();
+        rs.insert(Range { start: 0, end: 9 });
+        rs.insert(Range { start: 10, end: 19 });
+        rs.insert(Range { start: 30, end: 39 });
+
+        assert_eq!(rs.entries().len(), 2);
+        assert_eq!(rs.sum(), Some(30));
+    }
+
+    #[test_case]
+    fn remove_splits_ranges() {
+        let mut rs = RangeSet::new();
+        rs.insert(Range { start: 0, end: 99 });
+        rs.remove(Range { start: 10, end: 19 });
+
+        assert_eq!(rs.entries().len(), 2);
+        assert_eq!(rs.sum(), Some(90));
+    }
+
+    #[test_case]
+    fn allocate_aligns_and_takes_the_padding() {
+        let mut rs = RangeSet::new();
+        rs.insert(Range {
+            start: 0x1001,
+            end: 0x4fff,
+        });
+
+        assert_eq!(rs.allocate(0x1000, 0x1000), Some(0x2000));
+        assert_eq!(rs.sum(), Some(0x2000));
+        assert_eq!(rs.allocate(0x4000, 0x1000), None);
+    }
+}
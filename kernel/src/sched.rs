@@ -0,0 +1,447 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{
+    apic::{self, Ipi},
+    cmdline::{self, SchedPolicy},
+    core_locals::{self, CoreLocals},
+    cpu,
+    interrupts::{GateOptions, Interrupt, InterruptFrame, Registers},
+    paging,
+    task::{Context, Task},
+};
+
+// How many ticks pass between two balancing runs on a core
+const BALANCE_TICKS: u64 = 10;
+
+// Sent to an idle core to make it look for work again
+static RESCHEDULE_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+// Picks the reschedule IPI vector, must run on the BSP before the APs start
+pub fn init() -> Option<()> {
+    let mut interrupts = core!().interrupt_state.lock();
+    let interrupts = interrupts.as_mut()?;
+
+    let vector = interrupts.allocate_vector()?;
+    interrupts.register(
+        vector,
+        GateOptions::default(),
+        reschedule_ipi,
+        core::ptr::null_mut(),
+    )?;
+
+    RESCHEDULE_VECTOR.store(vector, Ordering::SeqCst);
+
+    Some(())
+}
+
+// Nothing to do here, waking the core out of `hlt` is the point
+fn reschedule_ipi(_interrupt: &mut Interrupt, _context: *mut ()) {
+    apic::eoi();
+}
+
+// Gets `core` out of `hlt`, whatever it was waiting for
+pub fn interrupt_core(core: &CoreLocals) {
+    let vector = RESCHEDULE_VECTOR.load(Ordering::SeqCst);
+
+    if vector != 0 && core.id != core!().id {
+        apic::send_ipi(core.apic_id, Ipi::Fixed(vector));
+    }
+}
+
+fn wake(core: &CoreLocals) {
+    if core.idle.load(Ordering::SeqCst) {
+        interrupt_core(core);
+    }
+}
+
+// Wakes one idle core so it can steal some of our work
+pub fn kick_idle_core() {
+    if cmdline::get().sched == SchedPolicy::Local {
+        return;
+    }
+
+    if let Some(core) = core_locals::cores().find(|core| core.idle.load(Ordering::SeqCst)) {
+        wake(core);
+    }
+}
+
+// Queues `task` on the least loaded core it is allowed to run on, used for
+// moving tasks and waking blocked ones. Must not be called with this core's
+// task list locked
+pub fn enqueue(task: Box<Task>) {
+    let target = core_locals::cores()
+        .filter(|core| task.allowed_on(core.id))
+        .min_by_key(|core| core.tasks.lock().len());
+
+    match target {
+        Some(core) => {
+            log::debug!("Queueing task {} on core {}", task.id(), core.id);
+
+            core.tasks.lock().push(task);
+            wake(core);
+        }
+        None => log::error!("Task {} has no core left to run on, dropping it", task.id()),
+    }
+}
+
+// Takes a task we may run off the busiest other core. The task a core is
+// running is never taken
+fn steal() -> bool {
+    let me = core!().id;
+
+    let victim = core_locals::cores()
+        .filter(|core| core.id != me)
+        .max_by_key(|core| core.tasks.lock().len());
+
+    let victim = match victim {
+        Some(victim) => victim,
+        None => return false,
+    };
+
+    let task = {
+        let mut tasks = victim.tasks.lock();
+        let mut current_task_id = victim.current_task_id.lock();
+
+        let index = match (0..tasks.len())
+            .find(|&index| index != *current_task_id && tasks[index].allowed_on(me))
+        {
+            Some(index) => index,
+            None => return false,
+        };
+
+        if index < *current_task_id {
+            *current_task_id -= 1;
+        }
+
+        tasks.remove(index)
+    };
+
+    log::debug!(
+        "Core {} stole task {} from core {}",
+        me,
+        task.id(),
+        victim.id
+    );
+
+    core!().tasks.lock().push(task);
+    core!().steals.fetch_add(1, Ordering::Relaxed);
+
+    true
+}
+
+// Called from every tick. `user` says whether it interrupted user mode, the
+// task gets switched out on the way back if something else is waiting here
+pub fn tick(user: bool) {
+    let ticks = core!().ticks.fetch_add(1, Ordering::Relaxed) + 1;
+    if ticks % BALANCE_TICKS == 0 && cmdline::get().sched == SchedPolicy::Balance {
+        balance();
+    }
+
+    if user && core!().tasks.lock().len() > 1 {
+        core!().preempt.store(true, Ordering::SeqCst);
+    }
+}
+
+// Takes a task off the busiest core when it has at least two more queued than
+// we do, so busy cores even out without waiting for one to go idle
+fn balance() {
+    let me = core!().id;
+    let queued = core!().tasks.lock().len();
+
+    let busiest = core_locals::cores()
+        .filter(|core| core.id != me)
+        .map(|core| core.tasks.lock().len())
+        .max()
+        .unwrap_or(0);
+
+    if busiest >= queued + 2 {
+        steal();
+    }
+}
+
+// Saves the interrupted task and switches to the next one in our queue
+pub fn yield_current(frame: &InterruptFrame, regs: &Registers) -> ! {
+    switch_from(frame, regs, None)
+}
+
+// Takes the interrupted task off our queue and hands it to `park`, which keeps
+// it until whoever wakes it passes it to `enqueue`
+pub fn block_current(
+    frame: &InterruptFrame,
+    regs: &Registers,
+    park: &mut dyn FnMut(Box<Task>),
+) -> ! {
+    switch_from(frame, regs, Some(park))
+}
+
+// Like `block_current`, but from kernel code running for the current task. It
+// returns here once the task is entered again, possibly on another core
+pub fn block_kernel(park: &mut dyn FnMut(Box<Task>)) {
+    extern "C" fn block(
+        frame: &InterruptFrame,
+        regs: &Registers,
+        park: *mut &mut dyn FnMut(Box<Task>),
+    ) -> ! {
+        block_current(frame, regs, unsafe { &mut **park })
+    }
+
+    let mut park = park;
+
+    // Lays out what an interrupt would leave behind, set up to resume at the
+    // end. Every register but rax and rcx comes back as it was
+    unsafe {
+        core::arch::asm!(
+            "mov rax, rsp",
+            "and rsp, -16",
+            "mov rcx, ss",
+            "push rcx",
+            "push rax",
+            "pushfq",
+            "cli",
+            "mov rcx, cs",
+            "push rcx",
+            "lea rcx, [rip + 2f]",
+            "push rcx",
+            "push r15",
+            "push r14",
+            "push r13",
+            "push r12",
+            "push r11",
+            "push r10",
+            "push r9",
+            "push r8",
+            "push rdi",
+            "push rsi",
+            "push rbp",
+            "push rdx",
+            "push rcx",
+            "push rbx",
+            "push rax",
+            "mov rdi, rsp",
+            "add rdi, 15*8",
+            "mov rsi, rsp",
+            "call {block}",
+            "2:",
+            block = sym block,
+            in("rdx") &mut park as *mut &mut dyn FnMut(Box<Task>),
+            out("rax") _,
+            out("rcx") _,
+        )
+    }
+}
+
+// Whether we run on the kernel stack of the task current on this core, rather
+// than during boot, on the core's own stack or in the idle loop
+pub fn on_task_stack() -> bool {
+    let rsp: usize;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+
+    let tasks = core!().tasks.lock();
+    let current_task_id = core!().current_task_id.lock();
+
+    match tasks.get(*current_task_id) {
+        Some(task) => task.on_kernel_stack(rsp),
+        None => false,
+    }
+}
+
+// Takes the task running on this core off our queue for good and moves on.
+// The last thread of a process takes what the process holds with it
+pub fn exit_current() -> ! {
+    on_core_stack(&mut || {
+        let (task, next) = {
+            let mut tasks = core!().tasks.lock();
+            let mut current_task_id = core!().current_task_id.lock();
+
+            let task = tasks.remove(*current_task_id);
+            if *current_task_id >= tasks.len() {
+                *current_task_id = 0;
+            }
+
+            (task, tasks.get(*current_task_id).map(|next| next.handoff()))
+        };
+
+        // Its kernel stack is only given up once we are off it, and dropping
+        // what it held can wake other tasks, which needs the queues unlocked
+        core!().on_stack_of.store(0, Ordering::SeqCst);
+        drop(task);
+
+        match next {
+            Some(next) => {
+                core!().switches.fetch_add(1, Ordering::Relaxed);
+                next.enter()
+            }
+            None => idle(),
+        }
+    })
+}
+
+// Continues in `f` on this core's own stack and the kernel page table, the
+// task we were running may be freed from here on. `f` must not return. Must be
+// called with interrupts off
+fn on_core_stack(f: &mut dyn FnMut()) -> ! {
+    extern "C" fn run(f: *mut &mut dyn FnMut()) {
+        unsafe { (*f)() }
+    }
+
+    let mut f = f;
+    let stack = core!().stack.load(Ordering::SeqCst);
+    unsafe { paging::load_kernel() };
+
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {stack}",
+            "call {run}",
+            "ud2",
+            stack = in(reg) stack,
+            run = sym run,
+            in("rdi") &mut f as *mut &mut dyn FnMut(),
+            options(noreturn)
+        )
+    }
+}
+
+// Must be called with the kernel GS base and page table loaded. The context is
+// saved while we are still on the task's kernel stack, the rest happens on our
+// own. Nobody enters the task before we are done with its stack, so `park`
+// can still use what lives on there
+fn switch_from(
+    frame: &InterruptFrame,
+    regs: &Registers,
+    park: Option<&mut dyn FnMut(Box<Task>)>,
+) -> ! {
+    {
+        let mut tasks = core!().tasks.lock();
+        let current_task_id = core!().current_task_id.lock();
+
+        tasks[*current_task_id].save_context(Context {
+            regs: *regs,
+            rip: frame.rip as usize,
+            rsp: frame.rsp as usize,
+            rflags: frame.rflags,
+            cs: frame.cs,
+            ss: frame.ss,
+        });
+    }
+
+    let mut park = park;
+    on_core_stack(&mut || reschedule(park.take()))
+}
+
+// The task lists are only locked while picking, every guard is dropped before
+// the switch
+fn reschedule(park: Option<&mut dyn FnMut(Box<Task>)>) -> ! {
+    let (next, leaving, queued) = {
+        let mut tasks = core!().tasks.lock();
+        let mut current_task_id = core!().current_task_id.lock();
+        let id = *current_task_id;
+
+        // A task that blocks, or whose affinity no longer includes us, leaves
+        // our queue and the next one slides into its slot
+        let leaving = if park.is_none() && tasks[id].allowed_on(core!().id) {
+            *current_task_id = id + 1;
+            None
+        } else {
+            Some(tasks.remove(id))
+        };
+
+        if *current_task_id >= tasks.len() {
+            *current_task_id = 0;
+        }
+
+        let next = tasks.get(*current_task_id).map(|next| next.handoff());
+
+        (next, leaving, tasks.len())
+    };
+
+    // Other cores lock their task list and then ours while balancing, and a
+    // parked task may be woken on another core right away, so ours must be
+    // unlocked here
+    if let Some(task) = leaving {
+        match park {
+            Some(park) => park(task),
+            None => enqueue(task),
+        }
+    }
+
+    if queued > 1 {
+        kick_idle_core();
+    }
+
+    core!().on_stack_of.store(0, Ordering::SeqCst);
+
+    match next {
+        Some(next) => {
+            core!().switches.fetch_add(1, Ordering::Relaxed);
+            next.enter()
+        }
+        None => idle(),
+    }
+}
+
+// What a core runs when it has no task, picks tasks up as they get assigned
+// to it or steals them from other cores
+pub fn idle() -> ! {
+    loop {
+        let next = {
+            let tasks = core!().tasks.lock();
+            let current_task_id = core!().current_task_id.lock();
+
+            tasks.get(*current_task_id).map(|next| next.handoff())
+        };
+
+        if let Some(next) = next {
+            core!().idle.store(false, Ordering::SeqCst);
+            core!().switches.fetch_add(1, Ordering::Relaxed);
+            next.enter()
+        }
+
+        if cmdline::get().sched == SchedPolicy::Balance && steal() {
+            continue;
+        }
+
+        // Interrupts stay off until `hlt`, so a wakeup sent after we marked
+        // ourselves idle is not lost
+        core!().idle.store(true, Ordering::SeqCst);
+
+        let start = unsafe { cpu::rdtsc() };
+        unsafe { core::arch::asm!("sti", "hlt", "cli") };
+        let end = unsafe { cpu::rdtsc() };
+
+        core!()
+            .idle_cycles
+            .fetch_add(end - start, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Utilization {
+    pub total_cycles: u64,
+    pub idle_cycles: u64,
+    pub switches: u64,
+    pub steals: u64,
+    pub queued: usize,
+}
+
+impl Utilization {
+    // Busy time in tenths of a percent
+    pub fn busy_permille(&self) -> u64 {
+        if self.total_cycles == 0 {
+            return 0;
+        }
+
+        let busy = self.total_cycles.saturating_sub(self.idle_cycles);
+        ((busy as u128 * 1000) / self.total_cycles as u128) as u64
+    }
+}
+
+pub fn utilization(core: &CoreLocals) -> Utilization {
+    Utilization {
+        total_cycles: unsafe { cpu::rdtsc() }.saturating_sub(core.online_tsc),
+        idle_cycles: core.idle_cycles.load(Ordering::Relaxed),
+        switches: core.switches.load(Ordering::Relaxed),
+        steals: core.steals.load(Ordering::Relaxed),
+        queued: core.tasks.lock().len(),
+    }
+}
@@ -1,3 +1,40 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    cpu,
+    handle::Device,
+    interrupts::Interrupt,
+    irq, monitor, sched,
+    sync::{IrqLockCell, IrqLockCellGuard, Semaphore},
+    task::Task,
+};
+
+const COM1_IRQ: u8 = 4;
+
+// 16550 registers, relative to the base port. The first two double as the
+// baud rate divisor while DLAB is set in the line control register
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_DATA_READY: u8 = 1 << 0;
+const LINE_THR_EMPTY: u8 = 1 << 5;
+const LINE_TX_IDLE: u8 = 1 << 6;
+
+// How many bytes the transmit FIFO takes once it reports empty
+const TX_FIFO_SIZE: usize = 16;
+
+// 115200 baud
+const BAUD_DIVISOR: u16 = 1;
+
+const RX_BUFFER_SIZE: usize = 4096;
+
+// Writes straight to COM1 without any locking, for when nothing else can be
+// trusted anymore, i.e. panics
 pub struct EmergencySerial;
 
 impl EmergencySerial {
@@ -21,3 +58,259 @@ impl core::fmt::Write for EmergencySerial {
         Ok(())
     }
 }
+
+// Received bytes waiting to be read, the oldest ones are dropped when nobody
+// keeps up
+struct RingBuffer {
+    data: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    fn push(&mut self, byte: u8) {
+        if self.len == RX_BUFFER_SIZE {
+            self.head = (self.head + 1) % RX_BUFFER_SIZE;
+            self.len -= 1;
+        }
+
+        self.data[(self.head + self.len) % RX_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+
+        Some(byte)
+    }
+}
+
+// Keeps lines from different cores from interleaving
+static TX: IrqLockCell<()> = IrqLockCell::new(());
+
+static RX: IrqLockCell<RingBuffer> = IrqLockCell::new(RingBuffer {
+    data: [0; RX_BUFFER_SIZE],
+    head: 0,
+    len: 0,
+});
+
+// A 16550 compatible UART at an IO port base
+pub struct Uart {
+    base: u16,
+}
+
+pub const COM1: Uart = Uart::new(0x3f8);
+pub const COM2: Uart = Uart::new(0x2f8);
+
+impl Uart {
+    pub const fn new(base: u16) -> Uart {
+        Uart { base }
+    }
+
+    unsafe fn read_reg(&self, reg: u16) -> u8 {
+        cpu::inb(self.base + reg)
+    }
+
+    unsafe fn write_reg(&self, reg: u16, val: u8) {
+        cpu::outb(self.base + reg, val)
+    }
+
+    // Programs the port for 115200 8N1 with FIFOs, with its interrupt still
+    // off. Fails if there is no UART
+    pub fn init(&self) -> Option<()> {
+        unsafe {
+            self.write_reg(INTERRUPT_ENABLE, 0);
+
+            self.write_reg(LINE_CONTROL, 0x80);
+            self.write_reg(DATA, BAUD_DIVISOR as u8);
+            self.write_reg(INTERRUPT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+
+            // 8 data bits, no parity, 1 stop bit, DLAB off again
+            self.write_reg(LINE_CONTROL, 0x03);
+
+            // Enable and clear both FIFOs, interrupt at 14 bytes
+            self.write_reg(FIFO_CONTROL, 0xc7);
+
+            // Check there is a UART at all by looping a byte back
+            self.write_reg(MODEM_CONTROL, 0x1e);
+            self.write_reg(DATA, 0xae);
+            if self.read_reg(DATA) != 0xae {
+                return None;
+            }
+
+            // DTR, RTS and OUT2, which gates the interrupt line
+            self.write_reg(MODEM_CONTROL, 0x0b);
+        }
+
+        Some(())
+    }
+
+    // Drops whatever came in before anyone listened, then asks for an
+    // interrupt per received byte
+    pub fn enable_receive_interrupt(&self) {
+        while self.poll().is_some() {}
+        unsafe { self.write_reg(INTERRUPT_ENABLE, 0x01) };
+    }
+
+    // Reads a byte straight from the UART if there is one, for code that runs
+    // with interrupts off and cannot wait for them
+    pub fn poll(&self) -> Option<u8> {
+        unsafe {
+            if self.read_reg(LINE_STATUS) & LINE_DATA_READY != 0 {
+                Some(self.read_reg(DATA))
+            } else {
+                None
+            }
+        }
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        for chunk in bytes.chunks(TX_FIFO_SIZE) {
+            unsafe {
+                while self.read_reg(LINE_STATUS) & LINE_THR_EMPTY == 0 {
+                    core::hint::spin_loop();
+                }
+
+                for &byte in chunk {
+                    self.write_reg(DATA, byte);
+                }
+            }
+        }
+    }
+
+    // Waits until everything written so far has left the wire
+    pub fn flush(&self) {
+        unsafe {
+            while self.read_reg(LINE_STATUS) & LINE_TX_IDLE == 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+// Sets up COM1 as the console and starts taking input on IRQ 4, must run
+// after the IO-APICs are set up
+pub fn init() -> Option<()> {
+    COM1.init()?;
+
+    // The IRQ is routed to this core. Keeping the monitor here means its
+    // interrupts being off also keep the IRQ from taking its input
+    let mut monitor = Box::new(Task::new_kernel(monitor_thread)?);
+    monitor.set_affinity(1 << core!().id);
+    sched::enqueue(monitor);
+
+    irq::register_isa(COM1_IRQ, receive)?;
+    COM1.enable_receive_interrupt();
+
+    Some(())
+}
+
+// Set after the monitor prefix came in, the next byte decides what it was
+static MAGIC_PENDING: AtomicBool = AtomicBool::new(false);
+
+// Released by the receive interrupt for every time the monitor was asked for
+static MONITOR_REQUESTS: Semaphore = Semaphore::new(0);
+
+// Runs the monitor whenever it is asked for, outside of the interrupt that
+// noticed. It polls the UART, so interrupts stay off while it runs
+fn monitor_thread() {
+    loop {
+        MONITOR_REQUESTS.acquire();
+
+        unsafe { cpu::disable_interrupts() };
+        monitor::run();
+        unsafe { cpu::enable_interrupts() };
+    }
+}
+
+fn receive(_gsi: u32, _interrupt: &mut Interrupt) {
+    let mut enter_monitor = false;
+
+    {
+        let mut rx = RX.lock();
+
+        while let Some(byte) = COM1.poll() {
+            if MAGIC_PENDING.swap(false, Ordering::SeqCst) {
+                if byte == monitor::MAGIC_KEY {
+                    enter_monitor = true;
+                    continue;
+                }
+
+                rx.push(monitor::MAGIC_PREFIX);
+            }
+
+            if byte == monitor::MAGIC_PREFIX {
+                MAGIC_PENDING.store(true, Ordering::SeqCst);
+            } else {
+                rx.push(byte);
+            }
+        }
+    }
+
+    // Only wakes the monitor thread, the EOI goes out as soon as we return
+    if enter_monitor {
+        MONITOR_REQUESTS.release();
+    }
+}
+
+// Holds the port for a whole message, e.g. one log line
+pub struct SerialWriter {
+    _tx: IrqLockCellGuard<'static, ()>,
+}
+
+impl SerialWriter {
+    pub fn write(&mut self, bytes: &[u8]) {
+        COM1.write(bytes)
+    }
+}
+
+impl core::fmt::Write for SerialWriter {
+    fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        self.write(string.as_bytes());
+        Ok(())
+    }
+}
+
+pub fn lock() -> SerialWriter {
+    SerialWriter { _tx: TX.lock() }
+}
+
+pub fn write(bytes: &[u8]) {
+    lock().write(bytes)
+}
+
+// Takes what has been received so far, never waits for more
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut rx = RX.lock();
+
+    let mut read = 0;
+    while read < buf.len() {
+        match rx.pop() {
+            Some(byte) => buf[read] = byte,
+            None => break,
+        }
+        read += 1;
+    }
+
+    read
+}
+
+// The serial port as a device tasks can hold a handle to
+pub struct SerialConsole;
+
+impl Device for SerialConsole {
+    fn read(&self, buf: &mut [u8]) -> Option<usize> {
+        Some(read(buf))
+    }
+
+    fn write(&self, buf: &[u8]) -> Option<usize> {
+        write(buf);
+        Some(buf.len())
+    }
+}
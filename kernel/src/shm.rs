@@ -0,0 +1,243 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
+use crate::{
+    handle::{Object, Rights, RIGHTS_ALL, RIGHT_EXECUTE, RIGHT_MAP, RIGHT_WRITE},
+    interrupts::Registers,
+    mm::{self, PhysAddr, PhysMem},
+    sync::LockCell,
+    syscall::ERROR,
+    task::{self, Task},
+};
+
+pub const MAX_SHM_SIZE: u64 = 64 * 1024 * 1024;
+pub const MAX_NAME_LEN: usize = 64;
+
+// Permissions for `shm_map`, each mapping gets its own
+pub const SHM_WRITE: u64 = 1 << 0;
+pub const SHM_EXEC: u64 = 1 << 1;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// Zeroed frames that any number of address spaces can map. They go back to the
+// allocator once the last handle, mapping and name referring to them is gone
+pub struct SharedMemory {
+    frames: Vec<PhysAddr>,
+}
+
+impl SharedMemory {
+    pub fn new(size: u64) -> Option<Arc<SharedMemory>> {
+        if size == 0 || size > MAX_SHM_SIZE {
+            return None;
+        }
+
+        let layout = Layout::from_size_align(PAGE_SIZE as usize, PAGE_SIZE as usize).ok()?;
+
+        // Dropping a half built object frees what it got so far
+        let mut shm = SharedMemory { frames: Vec::new() };
+        for _ in 0..(size + PAGE_SIZE - 1) / PAGE_SIZE {
+            shm.frames
+                .push(mm::PhysicalMemory.alloc_phys_zeroed(layout)?);
+        }
+
+        Some(Arc::new(shm))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * PAGE_SIZE
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            mm::PhysicalMemory.free_phys(frame, PAGE_SIZE as usize);
+        }
+    }
+}
+
+// A shared memory object mapped into a task at `base`
+pub struct Mapping {
+    base: u64,
+    object: Arc<SharedMemory>,
+}
+
+impl Mapping {
+    pub fn contains(&self, vaddr: u64) -> bool {
+        vaddr >= self.base && vaddr - self.base < self.object.size()
+    }
+
+    // Address of every page the mapping covers
+    pub fn pages(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.object.frames.len() as u64).map(move |page| self.base + page * PAGE_SIZE)
+    }
+}
+
+// Named objects stay around until unlinked, even with nothing mapping them
+struct Name {
+    name: Vec<u8>,
+    object: Arc<SharedMemory>,
+    // The most `shm_open` hands out, picked by the creator
+    rights: Rights,
+}
+
+static NAMES: LockCell<Vec<Name>> = LockCell::new(Vec::new());
+
+// Whether `vaddr` is part of shared memory, those pages must not be given away
+pub fn is_shared(task: &Task, vaddr: u64) -> bool {
+    task.mappings()
+        .iter()
+        .any(|mapping| mapping.contains(vaddr))
+}
+
+fn read_name(task: &Task, name: u64, len: u64) -> Option<Vec<u8>> {
+    if len as usize > MAX_NAME_LEN {
+        return None;
+    }
+
+    let mut buf = alloc::vec![0; len as usize];
+    task.read_user(name, &mut buf)?;
+    Some(buf)
+}
+
+fn create(regs: &Registers) -> Option<u64> {
+    task::with_current(|task| {
+        let object = SharedMemory::new(regs.rdi)?;
+
+        // An empty name makes an anonymous object, only reachable by handle
+        if regs.rdx != 0 {
+            let name = read_name(task, regs.rsi, regs.rdx)?;
+            let rights = regs.r10;
+            if rights & !RIGHTS_ALL != 0 {
+                return None;
+            }
+
+            let mut names = NAMES.lock();
+            if names.iter().any(|other| other.name == name) {
+                return None;
+            }
+
+            names.push(Name {
+                name,
+                object: object.clone(),
+                rights,
+            });
+        }
+
+        Some(task.handles().insert(Object::Memory(object), RIGHTS_ALL))
+    })
+}
+
+fn open(regs: &Registers) -> Option<u64> {
+    task::with_current(|task| {
+        let name = read_name(task, regs.rdi, regs.rsi)?;
+        let rights = regs.rdx;
+
+        let object = NAMES
+            .lock()
+            .iter()
+            .find(|other| other.name == name && other.rights & rights == rights)
+            .map(|other| other.object.clone())?;
+
+        Some(task.handles().insert(Object::Memory(object), rights))
+    })
+}
+
+fn unlink(regs: &Registers) -> Option<u64> {
+    let name = task::with_current(|task| read_name(task, regs.rdi, regs.rsi))?;
+
+    let mut names = NAMES.lock();
+    let index = names.iter().position(|other| other.name == name)?;
+    names.remove(index);
+
+    Some(0)
+}
+
+fn map(regs: &Registers) -> Option<u64> {
+    task::with_current(|task| {
+        let base = regs.rsi;
+        let write = regs.rdx & SHM_WRITE != 0;
+        let exec = regs.rdx & SHM_EXEC != 0;
+
+        // A mapping can only do what the handle allows
+        let mut rights = RIGHT_MAP;
+        if write {
+            rights |= RIGHT_WRITE;
+        }
+        if exec {
+            rights |= RIGHT_EXECUTE;
+        }
+
+        let object = match task.handles().get(regs.rdi, rights)? {
+            Object::Memory(object) => object.clone(),
+            _ => return None,
+        };
+
+        if base % PAGE_SIZE != 0 {
+            return None;
+        }
+
+        for (index, &frame) in object.frames.iter().enumerate() {
+            let vaddr = base.checked_add(index as u64 * PAGE_SIZE)?;
+
+            if task.map_user(vaddr, frame, write, exec).is_none() {
+                // Something was in the way, put everything back how it was
+                for index in 0..index as u64 {
+                    task.unmap_user(base + index * PAGE_SIZE);
+                }
+
+                return None;
+            }
+        }
+
+        task.mappings().push(Mapping { base, object });
+
+        Some(0)
+    })
+}
+
+fn unmap(regs: &Registers) -> Option<u64> {
+    task::with_current(|task| {
+        let mapping = {
+            let mut mappings = task.mappings();
+            let index = mappings
+                .iter()
+                .position(|mapping| mapping.base == regs.rdi)?;
+            mappings.remove(index)
+        };
+
+        for vaddr in mapping.pages() {
+            task.unmap_user(vaddr);
+        }
+
+        Some(0)
+    })
+}
+
+// shm_create(size, name, name_len, open_rights), returns a handle with every
+// right. `open_rights` is the most a named object can be opened with
+pub fn sys_shm_create(regs: &mut Registers) {
+    regs.rax = create(regs).unwrap_or(ERROR);
+}
+
+// shm_open(name, name_len, rights), returns a handle to a named object. Fails
+// if `rights` asks for more than its creator allows
+pub fn sys_shm_open(regs: &mut Registers) {
+    regs.rax = open(regs).unwrap_or(ERROR);
+}
+
+// shm_unlink(name, name_len), existing handles and mappings keep working
+pub fn sys_shm_unlink(regs: &mut Registers) {
+    regs.rax = unlink(regs).unwrap_or(ERROR);
+}
+
+// shm_map(handle, base, flags)
+pub fn sys_shm_map(regs: &mut Registers) {
+    regs.rax = map(regs).unwrap_or(ERROR);
+}
+
+// shm_unmap(base)
+pub fn sys_shm_unmap(regs: &mut Registers) {
+    regs.rax = unmap(regs).unwrap_or(ERROR);
+}
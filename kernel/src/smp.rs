@@ -0,0 +1,102 @@
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use stivale_boot::v2::{StivaleSmpInfo, StivaleStruct};
+
+use crate::{apic, core_locals, interrupts::Interrupts, mm, paging};
+
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+// How long to wait for an AP to check in before giving up on it
+const AP_STARTUP_SPINS: usize = 100_000_000;
+
+// APs that finished setting themselves up
+static APS_READY: AtomicUsize = AtomicUsize::new(0);
+
+// Limine jumps here on the AP with the stack set to `target_stack`, which only
+// exists in the kernel page table, so switch to it before touching the stack.
+// `extra_argument` (offset 24 in the SMP info) holds its root.
+#[naked]
+unsafe extern "C" fn ap_trampoline(_info: &'static StivaleSmpInfo) -> ! {
+    core::arch::asm!(
+        r#"
+        mov rax, [rdi + 24]
+        mov cr3, rax
+        call {}
+        ud2
+    "#,
+        sym ap_entry,
+        options(noreturn)
+    )
+}
+
+extern "C" fn ap_entry(info: &'static StivaleSmpInfo) -> ! {
+    core_locals::init(&mut mm::PhysicalMemory);
+
+    {
+        let mut interrupts = core!().interrupt_state.lock();
+        *interrupts = Some(Interrupts::init(&mut mm::PhysicalMemory));
+    }
+
+    apic::enable_local();
+    crate::time::init_core();
+
+    APS_READY.fetch_add(1, Ordering::SeqCst);
+
+    log::info!(
+        "Core {} online (processor {}, APIC ID {})",
+        core!().id,
+        info.processor_id,
+        info.lapic_id
+    );
+
+    crate::sched::idle()
+}
+
+// Starts every application processor the bootloader found, must be called on
+// the BSP once its own interrupt state is set up
+pub fn init(boot_info: &'static StivaleStruct) -> Option<()> {
+    let smp = boot_info.smp()?;
+
+    let kernel_table = paging::kernel_table()?;
+
+    let mut started = 0;
+    for cpu in smp.as_slice() {
+        if cpu.lapic_id == smp.bsp_lapic_id {
+            continue;
+        }
+
+        let stack = mm::alloc_kernel_stack(&mut mm::PhysicalMemory, AP_STACK_SIZE)?;
+
+        // The SMP info is owned by the bootloader which is polling
+        // `goto_address`, so everything else must be in place before that
+        unsafe {
+            let cpu = cpu as *const StivaleSmpInfo as *mut StivaleSmpInfo;
+            core::ptr::write_volatile(addr_of_mut!((*cpu).target_stack), stack.0 as u64);
+            core::ptr::write_volatile(addr_of_mut!((*cpu).extra_argument), kernel_table.0 as u64);
+
+            (*(addr_of_mut!((*cpu).goto_address) as *const AtomicU64))
+                .store(ap_trampoline as *const () as u64, Ordering::SeqCst);
+        }
+
+        started += 1;
+
+        // Bring them up one at a time, the log is easier to read and cores
+        // never race each other through early init
+        for _ in 0..AP_STARTUP_SPINS {
+            if APS_READY.load(Ordering::SeqCst) == started {
+                break;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    log::info!(
+        "{} of {} cores online",
+        APS_READY.load(Ordering::SeqCst) + 1,
+        started + 1
+    );
+
+    Some(())
+}
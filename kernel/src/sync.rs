@@ -1,15 +1,32 @@
 #![allow(dead_code)]
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use core::hint::spin_loop;
+use core::mem::{ManuallyDrop, MaybeUninit};
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "lock_debug")]
+use core::panic::Location;
+#[cfg(feature = "lock_debug")]
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+#[cfg(feature = "lock_debug")]
+use crate::lockdep;
+use crate::{core_locals, cpu, sched, task::Task, tlb};
 
 pub struct LockCell<T: ?Sized> {
     ticket: AtomicUsize,
 
     release: AtomicUsize,
 
+    // Core holding the lock and where it took it
+    #[cfg(feature = "lock_debug")]
+    owner: AtomicUsize,
+    #[cfg(feature = "lock_debug")]
+    owner_site: AtomicPtr<Location<'static>>,
+
     val: UnsafeCell<T>,
 }
 unsafe impl<T: ?Sized> Sync for LockCell<T> {}
@@ -20,6 +37,10 @@ impl<T> LockCell<T> {
             val: UnsafeCell::new(val),
             ticket: AtomicUsize::new(0),
             release: AtomicUsize::new(0),
+            #[cfg(feature = "lock_debug")]
+            owner: AtomicUsize::new(0),
+            #[cfg(feature = "lock_debug")]
+            owner_site: AtomicPtr::new(core::ptr::null_mut()),
         }
     }
 }
@@ -27,6 +48,15 @@ impl<T> LockCell<T> {
 impl<T: ?Sized> LockCell<T> {
     #[track_caller]
     pub fn lock(&self) -> LockCellGuard<T> {
+        self.lock_spinning(|| {})
+    }
+
+    // Locks, calling `spin` each time around while someone else holds it
+    #[track_caller]
+    fn lock_spinning(&self, mut spin: impl FnMut()) -> LockCellGuard<T> {
+        #[cfg(feature = "lock_debug")]
+        lockdep::before_lock(self.id(), Location::caller());
+
         // Get a ticket
         let ticket = self.ticket.fetch_add(1, Ordering::SeqCst);
 
@@ -35,33 +65,80 @@ impl<T: ?Sized> LockCell<T> {
         // Spin while our ticket doesn't match the release
         while self.release.load(Ordering::SeqCst) != ticket {
             if i == 1_000_000 {
+                #[cfg(feature = "lock_debug")]
+                if let Some(site) = unsafe { self.owner_site.load(Ordering::SeqCst).as_ref() } {
+                    panic!(
+                        "Waited too long to lock! Held by core token {} since {}",
+                        self.owner.load(Ordering::SeqCst),
+                        site
+                    );
+                }
+
                 panic!("Waited too long to lock!");
             }
 
+            spin();
             spin_loop();
             i += 1;
         }
 
         // At this point we have exclusive access
+        self.acquired();
         LockCellGuard { cell: self }
     }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<LockCellGuard<T>> {
+        // Only take a ticket if it would be served right away
+        let ticket = self.release.load(Ordering::SeqCst);
+
+        self.ticket
+            .compare_exchange(ticket, ticket + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
+
+        self.acquired();
+        Some(LockCellGuard { cell: self })
+    }
+
+    #[cfg(feature = "lock_debug")]
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    #[track_caller]
+    #[inline]
+    fn acquired(&self) {
+        #[cfg(feature = "lock_debug")]
+        {
+            let site = Location::caller();
+            self.owner.store(lockdep::core_token(), Ordering::SeqCst);
+            self.owner_site
+                .store(site as *const _ as *mut _, Ordering::SeqCst);
+            lockdep::locked(self.id(), site);
+        }
+    }
+
+    fn release(&self) {
+        #[cfg(feature = "lock_debug")]
+        {
+            self.owner.store(0, Ordering::SeqCst);
+            self.owner_site
+                .store(core::ptr::null_mut(), Ordering::SeqCst);
+            lockdep::unlocked(self.id());
+        }
+
+        self.release.fetch_add(1, Ordering::SeqCst);
+    }
 }
 
 pub struct LockCellGuard<'a, T: ?Sized> {
     cell: &'a LockCell<T>,
 }
 
-impl<'a, T: ?Sized> LockCellGuard<'a, T> {
-    pub unsafe fn release_lock(&self) {
-        // Release the lock
-        self.cell.release.fetch_add(1, Ordering::SeqCst);
-    }
-}
-
 impl<'a, T: ?Sized> Drop for LockCellGuard<'a, T> {
     fn drop(&mut self) {
         // Release the lock
-        self.cell.release.fetch_add(1, Ordering::SeqCst);
+        self.cell.release();
     }
 }
 
@@ -78,3 +155,803 @@ impl<'a, T: ?Sized> DerefMut for LockCellGuard<'a, T> {
         unsafe { &mut *self.cell.val.get() }
     }
 }
+
+// A `LockCell` that keeps interrupts off while it is held, for state that
+// interrupt handlers touch too. Without it a handler taking the lock from under
+// the code it interrupted would spin forever
+pub struct IrqLockCell<T: ?Sized> {
+    inner: LockCell<T>,
+}
+
+impl<T> IrqLockCell<T> {
+    pub const fn new(val: T) -> Self {
+        IrqLockCell {
+            inner: LockCell::new(val),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqLockCell<T> {
+    #[track_caller]
+    pub fn lock(&self) -> IrqLockCellGuard<T> {
+        let interrupts = cpu::interrupts_enabled();
+        unsafe { cpu::disable_interrupts() };
+
+        // The holder might be waiting on us to flush our TLB, and with
+        // interrupts off we would never see its IPI
+        IrqLockCellGuard {
+            guard: ManuallyDrop::new(self.inner.lock_spinning(tlb::service_pending)),
+            interrupts,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqLockCellGuard<T>> {
+        let interrupts = cpu::interrupts_enabled();
+        unsafe { cpu::disable_interrupts() };
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqLockCellGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts,
+            }),
+            None => {
+                if interrupts {
+                    unsafe { cpu::enable_interrupts() };
+                }
+
+                None
+            }
+        }
+    }
+}
+
+pub struct IrqLockCellGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<LockCellGuard<'a, T>>,
+
+    // Whether interrupts were on before we locked
+    interrupts: bool,
+}
+
+impl<'a, T: ?Sized> Drop for IrqLockCellGuard<'a, T> {
+    fn drop(&mut self) {
+        // Unlock first, an interrupt coming in right after might want it
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        if self.interrupts {
+            unsafe { cpu::enable_interrupts() };
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqLockCellGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqLockCellGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+enum Waiter {
+    // Goes back on a run queue when woken
+    Task(Box<Task>),
+    // Code outside of any task halts its core until `woken`, which lives on
+    // that core's stack, is set
+    Core {
+        core: usize,
+        woken: *const AtomicBool,
+    },
+}
+
+// Where the sleeping primitives below park their waiters. A task blocks and
+// the core runs something else meanwhile. Never wait from an interrupt handler
+pub struct WaitQueue {
+    waiters: IrqLockCell<Vec<Waiter>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqLockCell::new(Vec::new()),
+        }
+    }
+
+    // Sleeps until `condition` returns true. It is checked with the queue
+    // locked, so a change followed by a wake is never missed
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        if !sched::on_task_stack() {
+            return self.halt_until(condition);
+        }
+
+        let interrupts = cpu::interrupts_enabled();
+
+        loop {
+            // Interrupts go off first so the guard does not turn them back on
+            // when it is dropped on the way out of the task
+            unsafe { cpu::disable_interrupts() };
+
+            let waiters = self.waiters.lock();
+            if condition() {
+                break;
+            }
+
+            // The queue stays locked until the task is parked on it, so a wake
+            // in between waits for that instead of being missed
+            let mut waiters = Some(waiters);
+            sched::block_kernel(&mut |task| {
+                waiters.take().unwrap().push(Waiter::Task(task));
+            });
+        }
+
+        if interrupts {
+            unsafe { cpu::enable_interrupts() };
+        }
+    }
+
+    // Without a task to block, e.g. during boot, all we can do is halt
+    fn halt_until(&self, mut condition: impl FnMut() -> bool) {
+        let woken = AtomicBool::new(false);
+
+        loop {
+            let interrupts = {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    return;
+                }
+
+                woken.store(false, Ordering::SeqCst);
+                waiters.push(Waiter::Core {
+                    core: core!().id,
+                    woken: &woken,
+                });
+
+                cpu::interrupts_enabled()
+            };
+
+            unsafe { cpu::disable_interrupts() };
+
+            // Interrupts only come on inside `sti; hlt`, so a wakeup between
+            // the check and the halt still gets us out of it
+            while !woken.load(Ordering::SeqCst) {
+                unsafe { core::arch::asm!("sti", "hlt", "cli") };
+            }
+
+            if interrupts {
+                unsafe { cpu::enable_interrupts() };
+            }
+        }
+    }
+
+    // Must be called with the queue unlocked
+    fn wake(waiter: Waiter) {
+        match waiter {
+            Waiter::Task(task) => sched::enqueue(task),
+            Waiter::Core { core, woken } => {
+                unsafe { (*woken).store(true, Ordering::SeqCst) };
+
+                if let Some(core) = core_locals::get_core(core) {
+                    sched::interrupt_core(core);
+                }
+            }
+        }
+    }
+
+    pub fn wake_one(&self) {
+        let waiter = {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                return;
+            }
+
+            waiters.remove(0)
+        };
+
+        Self::wake(waiter);
+    }
+
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+
+        for waiter in waiters {
+            Self::wake(waiter);
+        }
+    }
+}
+
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    queue: WaitQueue,
+    val: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(val: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            queue: WaitQueue::new(),
+            val: UnsafeCell::new(val),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+
+        self.queue.wait_until(|| {
+            self.locked
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+
+        MutexGuard { mutex: self }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::SeqCst);
+        self.mutex.queue.wake_one();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.val.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.val.get() }
+    }
+}
+
+// Set in `RwLock::state` while a writer holds the lock, the rest counts readers
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    queue: WaitQueue,
+    val: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(val: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            queue: WaitQueue::new(),
+            val: UnsafeCell::new(val),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        self.state
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+                if state & WRITER == 0 {
+                    Some(state + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        let mut guard = None;
+        self.queue.wait_until(|| {
+            guard = self.try_read();
+            guard.is_some()
+        });
+
+        guard.unwrap()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        let mut guard = None;
+        self.queue.wait_until(|| {
+            guard = self.try_write();
+            guard.is_some()
+        });
+
+        guard.unwrap()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        // Only a writer can be waiting on readers
+        if self.lock.state.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.lock.queue.wake_one();
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.val.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::SeqCst);
+        self.lock.queue.wake_all();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.val.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.val.get() }
+    }
+}
+
+pub struct Semaphore {
+    count: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    pub fn acquire(&self) {
+        self.queue.wait_until(|| self.try_acquire());
+    }
+
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.queue.wake_one();
+    }
+}
+
+pub struct Condvar {
+    // Bumped by every notify, waiters sleep until it moves
+    generation: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: AtomicUsize::new(0),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    // Unlocks the mutex while waiting for a notify and locks it again before
+    // returning. Wakeups may be spurious, recheck the condition
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let generation = self.generation.load(Ordering::SeqCst);
+
+        drop(guard);
+        self.queue
+            .wait_until(|| self.generation.load(Ordering::SeqCst) != generation);
+
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.queue.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.queue.wake_all();
+    }
+}
+
+const ONCE_EMPTY: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_DONE: u8 = 2;
+
+// A value that is set exactly once, everyone racing to set it waits for the
+// winner
+pub struct Once<T> {
+    state: AtomicU8,
+    queue: WaitQueue,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(ONCE_EMPTY),
+            queue: WaitQueue::new(),
+            val: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::SeqCst) == ONCE_DONE {
+            Some(unsafe { (*self.val.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        if self
+            .state
+            .compare_exchange(ONCE_EMPTY, ONCE_RUNNING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            unsafe { (*self.val.get()).write(init()) };
+
+            self.state.store(ONCE_DONE, Ordering::SeqCst);
+            self.queue.wake_all();
+        } else {
+            self.queue
+                .wait_until(|| self.state.load(Ordering::SeqCst) == ONCE_DONE);
+        }
+
+        self.get().unwrap()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == ONCE_DONE {
+            unsafe { self.val.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+// A value computed on first use
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: F,
+}
+
+impl<T, F> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            once: Once::new(),
+            init,
+        }
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.once.call_once(&self.init)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{Condvar, Lazy, Mutex, Once, RwLock, Semaphore, WaitQueue};
+    use crate::{sched, task};
+
+    // Runs `f` on a kernel thread kept off this core, which only waits in
+    // here and never gets to run tasks while the tests do
+    fn spawn(f: impl FnOnce() + Send + 'static) {
+        let mut thread = Box::new(task::Task::new_kernel(f).unwrap());
+        thread.set_affinity(task::ALL_CORES & !(1 << core!().id));
+        sched::enqueue(thread);
+    }
+
+    fn wait_for_waiters(queue: &WaitQueue, count: usize) {
+        while queue.waiters.lock().len() < count {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[test_case]
+    fn mutex_is_held_until_the_guard_drops() {
+        let mutex = Mutex::new(1);
+
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+
+        assert_eq!(*mutex.try_lock().unwrap(), 2);
+        assert_eq!(*mutex.lock(), 2);
+    }
+
+    #[test_case]
+    fn rwlock_readers_share_and_writers_do_not() {
+        let lock = RwLock::new(1);
+
+        let first = lock.read();
+        let second = lock.try_read().unwrap();
+        assert_eq!(*first + *second, 2);
+        assert!(lock.try_write().is_none());
+
+        drop(first);
+        assert!(lock.try_write().is_none());
+        drop(second);
+
+        let mut writer = lock.write();
+        *writer = 3;
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(writer);
+
+        assert_eq!(*lock.read(), 3);
+    }
+
+    #[test_case]
+    fn semaphore_counts_down_and_up() {
+        let semaphore = Semaphore::new(2);
+
+        semaphore.acquire();
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.try_acquire());
+
+        semaphore.release();
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.try_acquire());
+    }
+
+    #[test_case]
+    fn condvar_notify_without_waiters() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+
+        condvar.notify_one();
+        condvar.notify_all();
+
+        assert!(condvar.queue.waiters.lock().is_empty());
+        assert_eq!(*mutex.try_lock().unwrap(), 0);
+    }
+
+    #[test_case]
+    fn wait_returns_at_once_when_the_condition_holds() {
+        let queue = WaitQueue::new();
+        let mut checks = 0;
+
+        queue.wait_until(|| {
+            checks += 1;
+            true
+        });
+
+        assert_eq!(checks, 1);
+        assert!(queue.waiters.lock().is_empty());
+    }
+
+    #[test_case]
+    fn once_runs_init_once() {
+        let once = Once::new();
+        assert!(once.get().is_none());
+
+        assert_eq!(*once.call_once(|| 1), 1);
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test_case]
+    fn lazy_computes_on_first_use() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let lazy: Lazy<usize> = Lazy::new(|| CALLS.fetch_add(1, Ordering::SeqCst) + 10);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 0);
+
+        assert_eq!(*lazy, 10);
+        assert_eq!(*lazy, 10);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn mutex_hands_over_oldest_waiter_first() {
+        static MUTEX: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+        static DONE: Semaphore = Semaphore::new(0);
+
+        let guard = MUTEX.lock();
+        for index in 0..3 {
+            spawn(move || {
+                MUTEX.lock().push(index);
+                DONE.release();
+            });
+            wait_for_waiters(&MUTEX.queue, index + 1);
+        }
+        drop(guard);
+
+        for _ in 0..3 {
+            DONE.acquire();
+        }
+        assert_eq!(*MUTEX.lock(), [0, 1, 2]);
+    }
+
+    #[test_case]
+    fn rwlock_readers_block_a_writer_together() {
+        static LOCK: RwLock<usize> = RwLock::new(0);
+        static INSIDE: AtomicUsize = AtomicUsize::new(0);
+        static LEAVE: Semaphore = Semaphore::new(0);
+        static DONE: Semaphore = Semaphore::new(0);
+
+        let mut writer = LOCK.write();
+        *writer = 1;
+        for _ in 0..2 {
+            spawn(|| {
+                let guard = LOCK.read();
+                INSIDE.fetch_add(1, Ordering::SeqCst);
+                LEAVE.acquire();
+
+                assert_eq!(*guard, 1);
+                drop(guard);
+                DONE.release();
+            });
+        }
+        wait_for_waiters(&LOCK.queue, 2);
+
+        // Both readers get in once the writer leaves, and keep the next one out
+        drop(writer);
+        while INSIDE.load(Ordering::SeqCst) < 2 {
+            core::hint::spin_loop();
+        }
+
+        spawn(|| {
+            *LOCK.write() = 2;
+            DONE.release();
+        });
+        wait_for_waiters(&LOCK.queue, 1);
+
+        LEAVE.release();
+        LEAVE.release();
+        for _ in 0..3 {
+            DONE.acquire();
+        }
+        assert_eq!(*LOCK.read(), 2);
+    }
+
+    #[test_case]
+    fn semaphore_wakes_one_waiter_per_release() {
+        static SEMAPHORE: Semaphore = Semaphore::new(0);
+        static ORDER: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+        static DONE: Semaphore = Semaphore::new(0);
+
+        for index in 0..3 {
+            spawn(move || {
+                SEMAPHORE.acquire();
+                ORDER.lock().push(index);
+                DONE.release();
+            });
+            wait_for_waiters(&SEMAPHORE.queue, index + 1);
+        }
+
+        SEMAPHORE.release();
+        DONE.acquire();
+        assert_eq!(*ORDER.lock(), [0]);
+        assert_eq!(SEMAPHORE.queue.waiters.lock().len(), 2);
+
+        SEMAPHORE.release();
+        SEMAPHORE.release();
+        DONE.acquire();
+        DONE.acquire();
+        assert_eq!(*ORDER.lock(), [0, 1, 2]);
+    }
+
+    #[test_case]
+    fn condvar_notify_one_then_all() {
+        static READY: Mutex<bool> = Mutex::new(false);
+        static CONDVAR: Condvar = Condvar::new();
+        static DONE: Semaphore = Semaphore::new(0);
+
+        for index in 0..3 {
+            spawn(|| {
+                let mut ready = READY.lock();
+                while !*ready {
+                    ready = CONDVAR.wait(ready);
+                }
+                drop(ready);
+                DONE.release();
+            });
+            wait_for_waiters(&CONDVAR.queue, index + 1);
+        }
+
+        *READY.lock() = true;
+        CONDVAR.notify_one();
+        DONE.acquire();
+        assert_eq!(CONDVAR.queue.waiters.lock().len(), 2);
+
+        CONDVAR.notify_all();
+        DONE.acquire();
+        DONE.acquire();
+        assert!(CONDVAR.queue.waiters.lock().is_empty());
+    }
+
+    #[test_case]
+    fn once_makes_racers_wait_for_the_winner() {
+        static ONCE: Once<usize> = Once::new();
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+        static INIT: Semaphore = Semaphore::new(0);
+        static DONE: Semaphore = Semaphore::new(0);
+
+        for _ in 0..3 {
+            spawn(|| {
+                let val = ONCE.call_once(|| {
+                    CALLS.fetch_add(1, Ordering::SeqCst);
+                    INIT.acquire();
+                    7
+                });
+                SEEN.fetch_add(*val, Ordering::SeqCst);
+                DONE.release();
+            });
+        }
+
+        // The winner blocks inside `init`, the other two behind it
+        wait_for_waiters(&ONCE.queue, 2);
+        INIT.release();
+
+        for _ in 0..3 {
+            DONE.acquire();
+        }
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(SEEN.load(Ordering::SeqCst), 21);
+    }
+}
@@ -0,0 +1,172 @@
+use alloc::boxed::Box;
+
+use crate::{
+    core_locals, futex,
+    handle::{self, Object, RIGHT_DUPLICATE, RIGHT_READ},
+    interrupts::{InterruptFrame, Registers},
+    ipc, logging, paging, sched, shm, task,
+    time::{self, NANOS_PER_SEC},
+};
+
+// The syscall number goes in rax, arguments in rdi, rsi, rdx, r10 and r8. The
+// result comes back in rax, !0 means the call failed. A second result, where
+// there is one, comes back in rdx
+
+pub const SYS_YIELD: u64 = 0;
+pub const SYS_SET_AFFINITY: u64 = 1;
+pub const SYS_GET_AFFINITY: u64 = 2;
+pub const SYS_CLOCK_GETTIME: u64 = 3;
+pub const SYS_NANOSLEEP: u64 = 4;
+pub const SYS_SLEEP: u64 = 5;
+pub const SYS_CHANNEL_CREATE: u64 = 6;
+pub const SYS_SEND: u64 = 7;
+pub const SYS_RECEIVE: u64 = 8;
+pub const SYS_CALL: u64 = 9;
+pub const SYS_REPLY: u64 = 10;
+pub const SYS_SHM_CREATE: u64 = 11;
+pub const SYS_SHM_OPEN: u64 = 12;
+pub const SYS_SHM_UNLINK: u64 = 13;
+pub const SYS_SHM_MAP: u64 = 14;
+pub const SYS_SHM_UNMAP: u64 = 15;
+pub const SYS_HANDLE_DUPLICATE: u64 = 16;
+pub const SYS_HANDLE_CLOSE: u64 = 17;
+pub const SYS_HANDLE_RIGHTS: u64 = 18;
+pub const SYS_TASK_SELF: u64 = 19;
+pub const SYS_TASK_ID: u64 = 20;
+pub const SYS_READ: u64 = 21;
+pub const SYS_WRITE: u64 = 22;
+pub const SYS_FUTEX_WAIT: u64 = 23;
+pub const SYS_FUTEX_WAKE: u64 = 24;
+pub const SYS_THREAD_CREATE: u64 = 25;
+pub const SYS_SET_FS_BASE: u64 = 26;
+pub const SYS_KLOG: u64 = 27;
+pub const SYS_THREAD_EXIT: u64 = 28;
+
+// Nanoseconds since boot, the only clock there is for now
+pub const CLOCK_MONOTONIC: u64 = 0;
+
+pub const ERROR: u64 = !0;
+
+// Mask of every core that is online right now
+fn online_cores() -> u64 {
+    core_locals::cores().fold(0, |mask, core| mask | (1 << core.id))
+}
+
+fn set_affinity(affinity: u64) -> u64 {
+    // Refuse masks that would leave the task nowhere to run
+    if affinity & online_cores() == 0 {
+        return ERROR;
+    }
+
+    let mut tasks = core!().tasks.lock();
+    let current_task_id = core!().current_task_id.lock();
+    tasks[*current_task_id].set_affinity(affinity);
+
+    0
+}
+
+fn clock_gettime(clock: u64) -> u64 {
+    match clock {
+        CLOCK_MONOTONIC => time::now(),
+        _ => ERROR,
+    }
+}
+
+// thread_create(entry, stack, arg, fs_base), returns a handle to the thread
+fn thread_create(regs: &Registers) -> Option<u64> {
+    if regs.rdi as usize >= paging::USER_END || regs.rsi as usize > paging::USER_END {
+        return None;
+    }
+
+    let thread =
+        task::with_current(|task| task.spawn_thread(regs.rdi, regs.rsi, regs.rdx, regs.r10))?;
+
+    let object = Object::Task(thread.process(), thread.id());
+    sched::enqueue(Box::new(thread));
+
+    Some(task::with_current(|task| {
+        task.handles().insert(object, RIGHT_READ | RIGHT_DUPLICATE)
+    }))
+}
+
+fn get_affinity() -> u64 {
+    let tasks = core!().tasks.lock();
+    let current_task_id = core!().current_task_id.lock();
+
+    tasks[*current_task_id].affinity() & online_cores()
+}
+
+pub extern "C" fn handler(frame: &InterruptFrame, regs: &mut Registers) {
+    if frame.cs & 0x3 == 0x3 {
+        unsafe { core::arch::asm!("swapgs") };
+    }
+
+    unsafe {
+        paging::load_kernel();
+    }
+
+    // Calls that block through `block_current` never come back here, switching
+    // to the next task takes care of GS and the page table
+    match regs.rax {
+        SYS_YIELD => {
+            regs.rax = 0;
+            sched::yield_current(frame, regs)
+        }
+        SYS_SET_AFFINITY => regs.rax = set_affinity(regs.rdi),
+        SYS_GET_AFFINITY => regs.rax = get_affinity(),
+        SYS_CLOCK_GETTIME => regs.rax = clock_gettime(regs.rdi),
+        SYS_NANOSLEEP => {
+            let ns = regs.rdi;
+            regs.rax = 0;
+            time::sleep_current(frame, regs, ns)
+        }
+        SYS_SLEEP => {
+            let ns = regs.rdi.saturating_mul(NANOS_PER_SEC);
+            regs.rax = 0;
+            time::sleep_current(frame, regs, ns)
+        }
+        SYS_CHANNEL_CREATE => ipc::sys_channel_create(regs),
+        SYS_SEND => ipc::sys_send(regs),
+        SYS_RECEIVE => ipc::sys_receive(frame, regs),
+        SYS_CALL => ipc::sys_call(frame, regs),
+        SYS_REPLY => ipc::sys_reply(regs),
+        SYS_SHM_CREATE => shm::sys_shm_create(regs),
+        SYS_SHM_OPEN => shm::sys_shm_open(regs),
+        SYS_SHM_UNLINK => shm::sys_shm_unlink(regs),
+        SYS_SHM_MAP => shm::sys_shm_map(regs),
+        SYS_SHM_UNMAP => shm::sys_shm_unmap(regs),
+        SYS_HANDLE_DUPLICATE => handle::sys_duplicate(regs),
+        SYS_HANDLE_CLOSE => handle::sys_close(regs),
+        SYS_HANDLE_RIGHTS => handle::sys_rights(regs),
+        SYS_TASK_SELF => handle::sys_task_self(regs),
+        SYS_TASK_ID => handle::sys_task_id(regs),
+        SYS_READ => handle::sys_read(regs),
+        SYS_WRITE => handle::sys_write(regs),
+        SYS_FUTEX_WAIT => futex::sys_futex_wait(frame, regs),
+        SYS_FUTEX_WAKE => futex::sys_futex_wake(regs),
+        SYS_THREAD_CREATE => regs.rax = thread_create(regs).unwrap_or(ERROR),
+        SYS_SET_FS_BASE => {
+            regs.rax = match task::with_current(|task| task.set_fs_base(regs.rdi)) {
+                Some(()) => 0,
+                None => ERROR,
+            }
+        }
+        SYS_KLOG => logging::sys_klog(regs),
+        // thread_exit(), the last thread of a process to exit ends the process
+        SYS_THREAD_EXIT => sched::exit_current(),
+        x => {
+            log::info!("Unknown syscall: {:#x}", x);
+            regs.rax = ERROR;
+        }
+    }
+
+    {
+        let tasks = core!().tasks.lock();
+        let task = &tasks[*core!().current_task_id.lock()];
+        unsafe { paging::load(task.page_table()) };
+    }
+
+    if frame.cs & 0x3 == 0x3 {
+        unsafe { core::arch::asm!("swapgs") };
+    }
+}
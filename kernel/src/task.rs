@@ -1,30 +1,215 @@
-use crate::cpu::to_usermode;
-use crate::interrupts::Registers;
-use crate::mm::{PhysMem, VirtAddr};
+use crate::core_locals::{self, MAX_CORES};
+use crate::cpu;
+use crate::handle::{HandleTable, Object, RIGHT_DUPLICATE, RIGHT_READ, RIGHT_WRITE};
+use crate::interrupts::{self, Registers};
+use crate::mm::{self, KernelStack, PhysAddr, PhysMem, VirtAddr};
 use crate::paging;
-use crate::paging::{PageTable, PAGE_NX, PAGE_USER};
+use crate::paging::{PageTable, PAGE_NX, PAGE_PRESENT, PAGE_USER, PAGE_WRITE};
+use crate::sched;
+use crate::serial::SerialConsole;
+use crate::shm::Mapping;
+use crate::sync::{LockCell, LockCellGuard};
+use crate::tlb;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::alloc::Layout;
-use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use xmas_elf::sections::ShType;
 
-#[derive(Default)]
+// Interrupts on, and IOPL 3 so tasks can talk to the serial port like the boot
+// task always could
+const USER_RFLAGS: u64 = (1 << 9) | (3 << 12) | (1 << 1);
+
+const USER_CS: u64 = 0x18 | 3;
+const USER_SS: u64 = 0x20 | 3;
+
+// Kernel threads run with interrupts on too
+const KERNEL_RFLAGS: u64 = (1 << 9) | (1 << 1);
+
+const KERNEL_CS: u64 = 0x08;
+const KERNEL_SS: u64 = 0x10;
+
+// `enter` depends on this layout
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
 pub struct Context {
     pub regs: Registers,
     pub rip: usize,
     pub rsp: usize,
+    pub rflags: u64,
+    // Kernel selectors for a task that blocked inside the kernel
+    pub cs: u64,
+    pub ss: u64,
+}
+
+// Everything needed to switch to a task. It is copied out while the task list
+// is locked, so the locks can be dropped before switching and nothing touches
+// the task once they are gone
+pub struct Handoff {
+    id: usize,
+    context: Context,
+    page_table: PhysAddr,
+    fs_base: u64,
+    kernel_stack: VirtAddr,
+}
+
+impl Handoff {
+    pub fn enter(self) -> ! {
+        log::debug!("Entering task {} at {:#x}", self.id, self.context.rip);
+
+        // The core that ran the task last may not be off its kernel stack yet
+        let me = core!().id;
+        while core_locals::cores()
+            .any(|core| core.id != me && core.on_stack_of.load(Ordering::SeqCst) == self.id)
+        {
+            tlb::service_pending();
+            core::hint::spin_loop();
+        }
+
+        core!().on_stack_of.store(self.id, Ordering::SeqCst);
+        core!().preempt.store(false, Ordering::SeqCst);
+        interrupts::set_kernel_stack(self.kernel_stack);
+
+        unsafe {
+            paging::load(self.page_table);
+            cpu::wrmsr(cpu::IA32_FS_BASE, self.fs_base);
+            enter(&self.context)
+        }
+    }
+}
+
+unsafe fn enter(context: &Context) -> ! {
+    core::arch::asm!(r#"
+        test qword ptr [rdi + 144], 3
+        jz 2f
+
+        mov bx, 0x20 | 3
+        mov ds, bx
+        mov es, bx
+
+        swapgs
+
+    2:
+        push qword ptr [rdi + 152]
+        push qword ptr [rdi + 128]
+        push qword ptr [rdi + 136]
+        push qword ptr [rdi + 144]
+        push qword ptr [rdi + 120]
+
+        mov rax, [rdi + 0]
+        mov rbx, [rdi + 8]
+        mov rcx, [rdi + 16]
+        mov rdx, [rdi + 24]
+        mov rbp, [rdi + 32]
+        mov rsi, [rdi + 40]
+        mov r8,  [rdi + 56]
+        mov r9,  [rdi + 64]
+        mov r10, [rdi + 72]
+        mov r11, [rdi + 80]
+        mov r12, [rdi + 88]
+        mov r13, [rdi + 96]
+        mov r14, [rdi + 104]
+        mov r15, [rdi + 112]
+        mov rdi, [rdi + 48]
+
+        iretq
+    "#, in("rdi") context, options(noreturn));
+}
+
+// Affinity mask allowing every core, bit N stands for core N
+pub const ALL_CORES: u64 = !0;
+
+const STACK_BASE: usize = 0xcafebabe00000000;
+
+// What the threads of one program share
+pub struct Process {
+    // Never changes, so switching to the process needs no lock
+    table: PhysAddr,
+    page_table: LockCell<PageTable>,
+    handles: LockCell<HandleTable>,
+    mappings: LockCell<Vec<Mapping>>,
+
+    // Threads that still exist, the last one to go releases what the process
+    // holds. Handles to the process itself would keep it alive otherwise
+    threads: AtomicUsize,
+}
+
+impl Process {
+    // Drops every handle and unmaps the shared memory, the frames of which
+    // belong to their objects rather than to us
+    fn release(&self) {
+        let handles = core::mem::take(&mut *self.handles.lock());
+        let mappings = core::mem::take(&mut *self.mappings.lock());
+
+        {
+            let mut page_table = self.page_table.lock();
+            for mapping in &mappings {
+                for vaddr in mapping.pages() {
+                    unsafe {
+                        page_table.unmap(
+                            &mut mm::PhysicalMemory,
+                            VirtAddr(vaddr as usize),
+                            paging::PageType::Page4K,
+                        );
+                    }
+                }
+            }
+        }
+
+        drop(handles);
+        drop(mappings);
+    }
+
+    // For the monitor, which must not wait on a lock the code it interrupted
+    // might hold
+    pub fn try_page_table(&self) -> Option<LockCellGuard<PageTable>> {
+        self.page_table.try_lock()
+    }
+}
+
+impl Drop for Process {
+    // No thread is left to run on the page table, so it and every page still
+    // mapped in the user half can go
+    fn drop(&mut self) {
+        let kernel_table = paging::kernel_table().expect("No kernel page table");
+
+        // Kernel threads borrow the kernel's own
+        if self.table == kernel_table {
+            return;
+        }
+
+        unsafe {
+            self.page_table
+                .lock()
+                .free(&mut mm::PhysicalMemory, kernel_table)
+        };
+    }
 }
 
+// A thread, the unit the scheduler runs. It enters the kernel on its own stack,
+// though a blocking system call never resumes on it
 pub struct Task {
     id: usize,
     context: Context,
-    page_table: PageTable,
+    affinity: u64,
+    fs_base: u64,
+    kernel_stack: KernelStack,
+    process: Arc<Process>,
 }
 
+impl Drop for Task {
+    fn drop(&mut self) {
+        if self.process.threads.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.process.release();
+        }
+    }
+}
+
+static TASK_ID: AtomicUsize = AtomicUsize::new(1);
+
 impl Task {
     pub fn new(allocator: &mut dyn PhysMem, mut page_table: PageTable) -> Option<Task> {
-        static TASK_ID: AtomicUsize = AtomicUsize::new(1);
-
-        const STACK_BASE: usize = 0xcafebabe00000000;
         let stack_phys = allocator.alloc_phys_zeroed(Layout::from_size_align(4096, 4096).ok()?)?;
 
         unsafe {
@@ -39,20 +224,153 @@ impl Task {
             )?;
         }
 
+        // Every task starts out with the serial console as handle 1 and the
+        // kernel log as handle 2, which it can pass on to whoever should see it
+        let mut handles = HandleTable::default();
+        handles.insert(
+            Object::Device(Arc::new(SerialConsole)),
+            RIGHT_READ | RIGHT_WRITE | RIGHT_DUPLICATE,
+        );
+        handles.insert(Object::Log, RIGHT_READ | RIGHT_DUPLICATE);
+
         Some(Task {
-            id: TASK_ID.fetch_add(1, core::sync::atomic::Ordering::SeqCst),
+            id: TASK_ID.fetch_add(1, Ordering::SeqCst),
             context: Context {
                 rsp: STACK_BASE + 4096,
+                rflags: USER_RFLAGS,
+                cs: USER_CS,
+                ss: USER_SS,
                 ..Default::default()
             },
-            page_table,
+            affinity: ALL_CORES,
+            fs_base: 0,
+            kernel_stack: KernelStack::new()?,
+            process: Arc::new(Process {
+                table: page_table.table(),
+                page_table: LockCell::new(page_table),
+                handles: LockCell::new(handles),
+                mappings: LockCell::new(Vec::new()),
+                threads: AtomicUsize::new(1),
+            }),
+        })
+    }
+
+    // Another thread in our process, starting at `entry` with `arg` in rdi.
+    // The caller provides its user stack
+    pub fn spawn_thread(&self, entry: u64, stack: u64, arg: u64, fs_base: u64) -> Option<Task> {
+        let kernel_stack = KernelStack::new()?;
+        self.process.threads.fetch_add(1, Ordering::SeqCst);
+
+        let mut context = Context {
+            rip: entry as usize,
+            rsp: stack as usize,
+            rflags: USER_RFLAGS,
+            cs: USER_CS,
+            ss: USER_SS,
+            ..Default::default()
+        };
+        context.regs.rdi = arg;
+
+        Some(Task {
+            id: TASK_ID.fetch_add(1, Ordering::SeqCst),
+            context,
+            affinity: self.affinity,
+            fs_base,
+            kernel_stack,
+            process: self.process.clone(),
         })
     }
 
+    // A thread that runs `f` in the kernel, on the kernel page table, and exits
+    // once it returns. Nothing preempts it, it runs until it blocks
+    pub fn new_kernel(f: impl FnOnce() + Send + 'static) -> Option<Task> {
+        extern "C" fn start(f: *mut Box<dyn FnOnce() + Send>) -> ! {
+            let f = unsafe { Box::from_raw(f) };
+            f();
+
+            unsafe { cpu::disable_interrupts() };
+            sched::exit_current()
+        }
+
+        let table = paging::kernel_table()?;
+        let kernel_stack = KernelStack::new()?;
+
+        // Boxed twice for a thin pointer to pass in rdi
+        let f: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(f));
+
+        let mut context = Context {
+            rip: start as *const () as usize,
+            // As if `start` had been called
+            rsp: kernel_stack.top().0 - 8,
+            rflags: KERNEL_RFLAGS,
+            cs: KERNEL_CS,
+            ss: KERNEL_SS,
+            ..Default::default()
+        };
+        context.regs.rdi = Box::into_raw(f) as u64;
+
+        Some(Task {
+            id: TASK_ID.fetch_add(1, Ordering::SeqCst),
+            context,
+            affinity: ALL_CORES,
+            fs_base: 0,
+            kernel_stack,
+            process: Arc::new(Process {
+                table,
+                page_table: LockCell::new(unsafe { PageTable::from_table(table) }),
+                handles: LockCell::new(HandleTable::default()),
+                mappings: LockCell::new(Vec::new()),
+                threads: AtomicUsize::new(1),
+            }),
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn affinity(&self) -> u64 {
+        self.affinity
+    }
+
+    // Takes effect the next time the task is scheduled
+    pub fn set_affinity(&mut self, affinity: u64) {
+        self.affinity = affinity
+    }
+
+    pub fn allowed_on(&self, core: usize) -> bool {
+        core < MAX_CORES && self.affinity & (1 << core) != 0
+    }
+
+    // Only for the running task, it is loaded into the MSR straight away
+    pub fn set_fs_base(&mut self, fs_base: u64) -> Option<()> {
+        if fs_base as usize >= paging::USER_END {
+            return None;
+        }
+
+        self.fs_base = fs_base;
+        unsafe { cpu::wrmsr(cpu::IA32_FS_BASE, fs_base) };
+
+        Some(())
+    }
+
+    pub fn process(&self) -> Arc<Process> {
+        self.process.clone()
+    }
+
+    pub fn handles(&self) -> LockCellGuard<HandleTable> {
+        self.process.handles.lock()
+    }
+
+    pub fn mappings(&self) -> LockCellGuard<Vec<Mapping>> {
+        self.process.mappings.lock()
+    }
+
     pub fn load_elf(&mut self, allocator: &mut dyn PhysMem, elf: &[u8]) -> Option<()> {
         let user_elf = xmas_elf::ElfFile::new(elf).unwrap();
         self.context.rip = user_elf.header.pt2.entry_point() as usize;
 
+        let mut page_table = self.process.page_table.lock();
         for section in user_elf.section_iter() {
             if section.get_type().unwrap() != ShType::ProgBits {
                 continue;
@@ -72,11 +390,11 @@ impl Task {
                 );
 
                 unsafe {
-                    self.page_table.map_raw(
+                    page_table.map_raw(
                         allocator,
                         VirtAddr(base as usize + (page * 4096)),
                         paging::PageType::Page4K,
-                        (phys.0 + (page * 4096)) | PAGE_USER | 3,
+                        phys.0 | PAGE_USER | 3,
                         true,
                         true,
                         true,
@@ -85,46 +403,210 @@ impl Task {
             }
 
             {
-                let kernel_page_table = core!().kernel_page_table.lock();
+                let kernel_page_table = paging::KERNEL_PAGE_TABLE.lock();
 
                 unsafe {
-                    self.page_table.write_to_as_slice(
-                        base as *mut u8,
-                        data,
-                        kernel_page_table.as_ref(),
-                    )
+                    page_table.write_to_as_slice(base as *mut u8, data, kernel_page_table.as_ref())
                 };
 
                 log::debug!("{:#?}", kernel_page_table.as_ref());
             }
         }
 
-        {
-            let mut tasks = core!().tasks.lock();
-            if let Some(_) = tasks.get(self.id) {
-                panic!("Task with the id {} is already loaded", self.id);
-            } else {
-                tasks.push(unsafe { &mut *(self as *mut _) });
-            }
+        Some(())
+    }
+
+    pub fn handoff(&self) -> Handoff {
+        Handoff {
+            id: self.id,
+            context: self.context,
+            page_table: self.process.table,
+            fs_base: self.fs_base,
+            kernel_stack: self.kernel_stack.top(),
         }
+    }
 
-        Some(())
+    // Where the task was when it last stopped running
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn save_context(&mut self, context: Context) {
+        self.context = context
+    }
+
+    // Whether `addr` is on the stack the task runs on in the kernel
+    pub fn on_kernel_stack(&self, addr: usize) -> bool {
+        let top = self.kernel_stack.top().0;
+        addr < top && addr >= top - mm::THREAD_STACK_SIZE
+    }
+
+    // Sets what the syscall a blocked task is sitting in returns
+    pub fn set_return(&mut self, rax: u64, rdx: u64) {
+        self.context.regs.rax = rax;
+        self.context.regs.rdx = rdx;
+    }
+
+    pub fn read_user(&self, vaddr: u64, buf: &mut [u8]) -> Option<()> {
+        self.process.page_table.lock().read_user(
+            &mut mm::PhysicalMemory,
+            VirtAddr(vaddr as usize),
+            buf,
+        )
+    }
+
+    pub fn write_user(&self, vaddr: u64, data: &[u8]) -> Option<()> {
+        self.process.page_table.lock().write_user(
+            &mut mm::PhysicalMemory,
+            VirtAddr(vaddr as usize),
+            data,
+        )
+    }
+
+    // Where the user byte at `vaddr` lives in physical memory
+    pub fn user_phys(&self, vaddr: u64) -> Option<PhysAddr> {
+        self.read_user(vaddr, &mut [0])?;
+        self.process
+            .page_table
+            .lock()
+            .translate(&mut mm::PhysicalMemory, VirtAddr(vaddr as usize))
+    }
+
+    // `T` has to be valid for any bit pattern
+    pub unsafe fn read_user_val<T: Copy>(&self, vaddr: u64) -> Option<T> {
+        let mut val = core::mem::MaybeUninit::<T>::uninit();
+        let buf =
+            core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, core::mem::size_of::<T>());
+
+        self.read_user(vaddr, buf)?;
+        Some(val.assume_init())
+    }
+
+    pub fn write_user_val<T: Copy>(&self, vaddr: u64, val: &T) -> Option<()> {
+        let data = unsafe {
+            core::slice::from_raw_parts(val as *const T as *const u8, core::mem::size_of::<T>())
+        };
+
+        self.write_user(vaddr, data)
     }
 
-    pub fn run(&self) -> ! {
-        log::debug!("Jumping to {:#x} in user-mode", self.context.rip);
+    // Takes the 4K user page at `vaddr` out of the address space, handing back
+    // its frame
+    pub fn unmap_user(&self, vaddr: u64) -> Option<PhysAddr> {
+        // Only pages user mode could touch itself
+        if vaddr & 0xfff != 0 {
+            return None;
+        }
+        self.read_user(vaddr, &mut [0])?;
+
+        let entry = unsafe {
+            self.process.page_table.lock().unmap(
+                &mut mm::PhysicalMemory,
+                VirtAddr(vaddr as usize),
+                paging::PageType::Page4K,
+            )?
+        };
+
+        Some(PhysAddr(entry & 0xffffffffff000))
+    }
+
+    // Maps `frame` as a user page at `vaddr`, which must not be mapped yet
+    pub fn map_user(
+        &self,
+        vaddr: u64,
+        frame: PhysAddr,
+        writable: bool,
+        executable: bool,
+    ) -> Option<()> {
+        if vaddr & 0xfff != 0 || vaddr as usize >= paging::USER_END {
+            return None;
+        }
+
+        let write = if writable { PAGE_WRITE } else { 0 };
+        let nx = if executable { 0 } else { PAGE_NX };
 
         unsafe {
-            self.page_table.switch_to();
-            to_usermode(self.context.rip, self.context.rsp)
+            self.process.page_table.lock().map_raw(
+                &mut mm::PhysicalMemory,
+                VirtAddr(vaddr as usize),
+                paging::PageType::Page4K,
+                frame.0 | nx | PAGE_USER | write | PAGE_PRESENT,
+                true,
+                false,
+                false,
+            )
         }
     }
 
-    pub fn save_context(&mut self, context: Context) {
-        self.context = context
+    // Root of the address space the task runs in
+    pub fn page_table(&self) -> PhysAddr {
+        self.process.table
+    }
+}
+
+// Runs `f` on the task running on this core, with our task list locked so no
+// other core looks at the task meanwhile. `f` must not block, queue or wake a
+// task, or drop anything that might, and must not call back in here
+pub fn with_current<R>(f: impl FnOnce(&mut Task) -> R) -> R {
+    let mut tasks = core!().tasks.lock();
+    let current_task_id = core!().current_task_id.lock();
+
+    f(&mut tasks[*current_task_id])
+}
+
+// Ends the task running on this core after it faulted
+pub fn kill_current() -> ! {
+    log::warn!("Killed task {}", with_current(|task| task.id));
+    sched::exit_current()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::Layout;
+
+    use super::Task;
+    use crate::mm::{self, PhysMem, PhysicalMemory, VirtAddr};
+    use crate::paging::{PageTable, PageType};
+
+    fn free_bytes() -> u64 {
+        mm::ALLOCATOR.lock().as_ref().unwrap().sum().unwrap()
     }
 
-    pub unsafe fn page_table(&self) -> *const PageTable {
-        &self.page_table
+    #[test_case]
+    fn dropping_a_process_keeps_the_identity_map() {
+        const SIZE: usize = 64 * 4096;
+
+        // In use by someone else, then mapped the way `new_kernel_pagetable`
+        // identity maps memory into every process
+        let block = PhysicalMemory
+            .alloc_phys_zeroed(Layout::from_size_align(SIZE, 4096).unwrap())
+            .unwrap();
+
+        let before = free_bytes();
+
+        let mut page_table = PageTable::new(&mut PhysicalMemory).unwrap();
+        for offset in (0..SIZE).step_by(4096) {
+            unsafe {
+                page_table
+                    .map_raw(
+                        &mut PhysicalMemory,
+                        VirtAddr(block.0 + offset),
+                        PageType::Page4K,
+                        (block.0 + offset) | 3,
+                        true,
+                        true,
+                        false,
+                    )
+                    .unwrap();
+            }
+        }
+
+        drop(Task::new(&mut PhysicalMemory, page_table).unwrap());
+
+        // Its tables and its stack went back, the block did not. A thread
+        // stack or heap taken on the way only makes the free list smaller
+        assert!(free_bytes() <= before);
+
+        PhysicalMemory.free_phys(block, SIZE);
     }
 }
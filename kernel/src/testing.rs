@@ -0,0 +1,93 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::{
+    cmdline, cpu,
+    power::{self, QemuExitCode},
+    serial::{self, EmergencySerial},
+};
+
+// Results go to COM1 a line at a time, for whatever runs QEMU to follow:
+//
+//   TEST START <count>
+//   TEST RUN <name>
+//   TEST PASS <name>
+//   TEST FAIL <name>   followed by the panic, the run ends there
+//   TEST DONE <passed> <count>
+//
+// QEMU then exits with 33 when everything passed and 35 when not
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        let _ = writeln!(serial::lock(), $($arg)*);
+    }};
+}
+
+// The test that is running, for the panic handler to name
+static CURRENT: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static CURRENT_LEN: AtomicUsize = AtomicUsize::new(0);
+
+pub trait Test {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+}
+
+impl<T: Fn()> Test for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        let name = self.name();
+
+        CURRENT_LEN.store(name.len(), Ordering::SeqCst);
+        CURRENT.store(name.as_ptr() as *mut u8, Ordering::SeqCst);
+
+        report!("TEST RUN {}", name);
+        self();
+        report!("TEST PASS {}", name);
+    }
+}
+
+// Called by the panic handler, a panic always fails whatever test was running.
+// It takes no locks, whoever panicked may hold the serial one
+pub fn fail() {
+    let ptr = CURRENT.load(Ordering::SeqCst);
+    if ptr.is_null() {
+        let _ = writeln!(EmergencySerial, "TEST FAIL <outside of any test>");
+        return;
+    }
+
+    let name = unsafe {
+        core::str::from_utf8_unchecked(core::slice::from_raw_parts(
+            ptr,
+            CURRENT_LEN.load(Ordering::SeqCst),
+        ))
+    };
+    let _ = writeln!(EmergencySerial, "TEST FAIL {}", name);
+}
+
+// Runs every `#[test_case]` once the kernel is up, `_start` calls this in test
+// builds before any user task exists. `test=<filter>` on the command line only
+// runs the tests with `filter` in their name
+pub fn run(tests: &[&dyn Test]) {
+    let filter = cmdline::get().test.unwrap_or("");
+    let count = tests
+        .iter()
+        .filter(|test| test.name().contains(filter))
+        .count();
+
+    report!("TEST START {}", count);
+
+    // A failing test panics and never gets counted
+    let mut passed = 0;
+    for test in tests.iter().filter(|test| test.name().contains(filter)) {
+        test.run();
+        passed += 1;
+    }
+
+    report!("TEST DONE {} {}", passed, count);
+
+    log::logger().flush();
+    power::exit_qemu(QemuExitCode::Success);
+    cpu::stop()
+}
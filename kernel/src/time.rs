@@ -0,0 +1,285 @@
+#![allow(dead_code)]
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use crate::{
+    acpi, apic, cpu,
+    interrupts::{GateOptions, Interrupt, InterruptFrame, Registers},
+    sched,
+    sync::{IrqLockCell, WaitQueue},
+    task::Task,
+};
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Every core gets a timer interrupt this often, it is also the resolution of
+// the timer wheel
+pub const TICK_NS: u64 = 10_000_000;
+
+const WHEEL_SLOTS: usize = 256;
+
+// How long to measure the TSC and the APIC timer against a reference clock
+const CALIBRATION_NS: u64 = 10_000_000;
+
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIG: usize = 0x10;
+const HPET_COUNTER: usize = 0xf0;
+
+const PIT_HZ: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+static TICK_VECTOR: AtomicU8 = AtomicU8::new(0);
+static APIC_TICKS_PER_TICK: AtomicU32 = AtomicU32::new(0);
+
+fn calibrate_hpet(base: usize) -> Option<u64> {
+    let read = |reg: usize| unsafe { core::ptr::read_volatile((base + reg) as *const u64) };
+
+    let capabilities = read(HPET_CAPABILITIES);
+    let period_fs = capabilities >> 32;
+
+    // The spec caps the period at 100ns
+    if period_fs == 0 || period_fs > 100_000_000 {
+        return None;
+    }
+
+    let mask = if capabilities & (1 << 13) != 0 {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+
+    unsafe {
+        core::ptr::write_volatile((base + HPET_CONFIG) as *mut u64, read(HPET_CONFIG) | 1);
+    }
+
+    let ticks = CALIBRATION_NS * 1_000_000 / period_fs;
+
+    let start = read(HPET_COUNTER);
+    let tsc_start = unsafe { cpu::rdtsc() };
+
+    while read(HPET_COUNTER).wrapping_sub(start) & mask < ticks {
+        core::hint::spin_loop();
+    }
+
+    let tsc = unsafe { cpu::rdtsc() } - tsc_start;
+    Some(tsc * (NANOS_PER_SEC / CALIBRATION_NS))
+}
+
+// Counts PIT channel 2 down once, it is always there but slow to access
+fn calibrate_pit() -> u64 {
+    let count = PIT_HZ * CALIBRATION_NS / NANOS_PER_SEC;
+
+    unsafe {
+        // Gate channel 2 on and keep the speaker off
+        let gate = cpu::inb(PIT_GATE);
+        cpu::outb(PIT_GATE, (gate & !0x02) | 0x01);
+
+        // Channel 2, low then high byte, interrupt on terminal count
+        cpu::outb(PIT_COMMAND, 0b1011_0000);
+        cpu::outb(PIT_CHANNEL2, count as u8);
+        cpu::outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+        let tsc_start = cpu::rdtsc();
+        while cpu::inb(PIT_GATE) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let tsc = cpu::rdtsc() - tsc_start;
+
+        cpu::outb(PIT_GATE, gate);
+
+        tsc * (NANOS_PER_SEC / CALIBRATION_NS)
+    }
+}
+
+// Calibrates the clocks and starts the tick on the BSP, must run after the
+// ACPI tables and the local APIC are set up
+pub fn init() -> Option<()> {
+    let hpet = acpi::HPET
+        .lock()
+        .as_ref()
+        .map(|hpet| hpet.address.address as usize);
+
+    let (tsc_hz, source) = match hpet.and_then(calibrate_hpet) {
+        Some(tsc_hz) => (tsc_hz, "HPET"),
+        None => (calibrate_pit(), "PIT"),
+    };
+
+    TSC_HZ.store(tsc_hz, Ordering::SeqCst);
+    BOOT_TSC.store(unsafe { cpu::rdtsc() }, Ordering::SeqCst);
+
+    log::info!(
+        "TSC runs at {}.{:03} MHz, calibrated against the {}",
+        tsc_hz / 1_000_000,
+        (tsc_hz / 1_000) % 1_000,
+        source
+    );
+
+    let vector = {
+        let mut interrupts = core!().interrupt_state.lock();
+        let interrupts = interrupts.as_mut()?;
+
+        let vector = interrupts.allocate_vector()?;
+        interrupts.register(vector, GateOptions::default(), tick, core::ptr::null_mut())?;
+        vector
+    };
+
+    // Interrupts are still off, and the count is too large to run out anyway
+    apic::start_timer(vector, u32::MAX, false);
+    let start = now();
+    while now() - start < CALIBRATION_NS {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - apic::timer_current();
+    apic::stop_timer();
+
+    let per_tick = (elapsed as u64 * TICK_NS / CALIBRATION_NS).clamp(1, u32::MAX as u64);
+    APIC_TICKS_PER_TICK.store(per_tick as u32, Ordering::SeqCst);
+    TICK_VECTOR.store(vector, Ordering::SeqCst);
+
+    init_core();
+
+    Some(())
+}
+
+// Starts the tick on the calling core
+pub fn init_core() {
+    let vector = TICK_VECTOR.load(Ordering::SeqCst);
+
+    if vector != 0 {
+        apic::start_timer(vector, APIC_TICKS_PER_TICK.load(Ordering::SeqCst), true);
+    }
+}
+
+pub fn tsc_to_ns(tsc: u64) -> u64 {
+    let tsc_hz = TSC_HZ.load(Ordering::Relaxed);
+    if tsc_hz == 0 {
+        return 0;
+    }
+
+    (tsc as u128 * NANOS_PER_SEC as u128 / tsc_hz as u128) as u64
+}
+
+// Nanoseconds since the clock was calibrated. The TSC is invariant and shared
+// by all cores on anything we run on, so this is monotonic across cores too
+pub fn now() -> u64 {
+    tsc_to_ns(unsafe { cpu::rdtsc() }.saturating_sub(BOOT_TSC.load(Ordering::Relaxed)))
+}
+
+pub enum TimerAction {
+    // Hands a blocked task back to the scheduler
+    Wake(Box<Task>),
+    Call(fn(*mut ()), *mut ()),
+}
+
+struct Timer {
+    deadline: u64,
+    action: TimerAction,
+}
+
+// A hashed timer wheel, a timer lives in the slot of the tick it expires in
+// and slots are scanned as the ticks go by
+struct Wheel {
+    slots: [Vec<Timer>; WHEEL_SLOTS],
+
+    // Earliest tick that may still have timers to fire
+    tick: u64,
+}
+
+const EMPTY_SLOT: Vec<Timer> = Vec::new();
+
+static WHEEL: IrqLockCell<Wheel> = IrqLockCell::new(Wheel {
+    slots: [EMPTY_SLOT; WHEEL_SLOTS],
+    tick: 0,
+});
+
+// Runs `action` once `now()` reaches `deadline`
+pub fn add_timer(deadline: u64, action: TimerAction) {
+    let mut wheel = WHEEL.lock();
+
+    let tick = (deadline / TICK_NS).max(wheel.tick);
+    wheel.slots[(tick % WHEEL_SLOTS as u64) as usize].push(Timer { deadline, action });
+}
+
+fn run_expired(now: u64) {
+    let mut expired = Vec::new();
+
+    {
+        // Every core ticks, one of them doing the work is enough
+        let mut wheel = match WHEEL.try_lock() {
+            Some(wheel) => wheel,
+            None => return,
+        };
+
+        let first = wheel.tick;
+        let last = (now / TICK_NS).min(first + WHEEL_SLOTS as u64 - 1);
+
+        for tick in first..=last {
+            let slot = &mut wheel.slots[(tick % WHEEL_SLOTS as u64) as usize];
+
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].deadline <= now {
+                    expired.push(slot.swap_remove(index).action);
+                } else {
+                    index += 1;
+                }
+            }
+        }
+
+        // The current tick is not over yet, it gets scanned again next time
+        wheel.tick = now / TICK_NS;
+    }
+
+    for action in expired {
+        match action {
+            TimerAction::Wake(task) => sched::enqueue(task),
+            TimerAction::Call(callback, context) => callback(context),
+        }
+    }
+}
+
+fn tick(interrupt: &mut Interrupt, _context: *mut ()) {
+    apic::eoi();
+    run_expired(now());
+
+    // Kernel code keeps the core until it blocks
+    sched::tick(interrupt.frame.cs & 0x3 == 0x3);
+}
+
+// Kernel code sleeping in `sleep`. The flags they wait on live on their
+// stacks, the queue does not, so waking never touches a sleeper that is gone
+static SLEEPERS: WaitQueue = WaitQueue::new();
+
+fn wake_sleeper(done: *mut ()) {
+    unsafe { (*(done as *const AtomicBool)).store(true, Ordering::SeqCst) };
+    SLEEPERS.wake_all();
+}
+
+// Blocks kernel code for at least `ns` nanoseconds
+pub fn sleep(ns: u64) {
+    let done = AtomicBool::new(false);
+
+    add_timer(
+        now().saturating_add(ns),
+        TimerAction::Call(wake_sleeper, &done as *const AtomicBool as *mut ()),
+    );
+
+    SLEEPERS.wait_until(|| done.load(Ordering::SeqCst));
+}
+
+// Blocks the interrupted task for at least `ns` nanoseconds and runs something
+// else meanwhile
+pub fn sleep_current(frame: &InterruptFrame, regs: &Registers, ns: u64) -> ! {
+    let deadline = now().saturating_add(ns);
+
+    sched::block_current(frame, regs, &mut |task| {
+        add_timer(deadline, TimerAction::Wake(task))
+    })
+}
@@ -0,0 +1,129 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    apic::{self, Ipi},
+    core_locals::{self, MAX_CORES},
+    cpu,
+    interrupts::{GateOptions, Interrupt},
+    mm::{PhysAddr, VirtAddr},
+    sync::LockCell,
+};
+
+// Past this many pages reloading CR3 is cheaper than invlpg-ing each one
+const FULL_FLUSH_PAGES: usize = 32;
+
+// Everything from here up is the kernel half, mapped the same everywhere
+const KERNEL_HALF: usize = 0xffff800000000000;
+
+static SHOOTDOWN_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+// Only one shootdown is in flight at a time, these describe it
+static SHOOTDOWN_LOCK: LockCell<()> = LockCell::new(());
+static SHOOTDOWN_TABLE: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_START: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_SIZE: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_PENDING: AtomicBool = AtomicBool::new(false);
+
+// Set for a core when it still has to flush the current shootdown
+static PENDING: [AtomicBool; MAX_CORES] = [NOT_PENDING; MAX_CORES];
+
+// Picks the shootdown IPI vector, must run on the BSP once interrupts are up
+pub fn init() -> Option<()> {
+    let mut interrupts = core!().interrupt_state.lock();
+    let interrupts = interrupts.as_mut()?;
+
+    let vector = interrupts.allocate_vector()?;
+    interrupts.register(
+        vector,
+        GateOptions::default(),
+        shootdown_ipi,
+        core::ptr::null_mut(),
+    )?;
+
+    SHOOTDOWN_VECTOR.store(vector, Ordering::SeqCst);
+
+    Some(())
+}
+
+fn flush_local(start: VirtAddr, size: usize) {
+    let pages = (size + 0xfff) / 0x1000;
+
+    if pages > FULL_FLUSH_PAGES {
+        unsafe { cpu::set_cr3(cpu::read_cr3() as usize) };
+    } else {
+        for page in 0..pages {
+            unsafe { cpu::invlpg(start.0 + page * 0x1000) };
+        }
+    }
+}
+
+// Flushes our part of the in flight shootdown if there is one for us. Code
+// spinning with interrupts off for long has to call this itself
+pub fn service_pending() {
+    if !PENDING[core!().id].swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let table = SHOOTDOWN_TABLE.load(Ordering::SeqCst);
+    let start = VirtAddr(SHOOTDOWN_START.load(Ordering::SeqCst));
+
+    if start.0 >= KERNEL_HALF || core!().active_page_table.load(Ordering::SeqCst) == table {
+        flush_local(start, SHOOTDOWN_SIZE.load(Ordering::SeqCst));
+    }
+
+    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::SeqCst);
+}
+
+fn shootdown_ipi(_interrupt: &mut Interrupt, _context: *mut ()) {
+    service_pending();
+    apic::eoi();
+}
+
+// Invalidates `size` bytes at `start` in `table` on every core that might have
+// them cached, and waits until they all did
+pub fn shootdown(table: PhysAddr, start: VirtAddr, size: usize) {
+    let kernel = start.0 >= KERNEL_HALF;
+    let me = core!().id;
+
+    if kernel || core!().active_page_table.load(Ordering::SeqCst) == table.0 {
+        flush_local(start, size);
+    }
+
+    let vector = SHOOTDOWN_VECTOR.load(Ordering::SeqCst);
+    if vector == 0 {
+        // Other cores are not up yet
+        return;
+    }
+
+    // We are most likely running with interrupts off, so keep answering other
+    // cores' shootdowns while waiting for ours, or we would deadlock
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+
+        service_pending();
+        core::hint::spin_loop();
+    };
+
+    SHOOTDOWN_TABLE.store(table.0, Ordering::SeqCst);
+    SHOOTDOWN_START.store(start.0, Ordering::SeqCst);
+    SHOOTDOWN_SIZE.store(size, Ordering::SeqCst);
+
+    let targets = core_locals::cores().filter(|core| {
+        core.id != me && (kernel || core.active_page_table.load(Ordering::SeqCst) == table.0)
+    });
+
+    for core in targets {
+        SHOOTDOWN_PENDING.fetch_add(1, Ordering::SeqCst);
+        PENDING[core.id].store(true, Ordering::SeqCst);
+        apic::send_ipi(core.apic_id, Ipi::Fixed(vector));
+    }
+
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,48 @@
+#!/bin/bash
+
+# Cargo runs this with the kernel ELF as its argument, see .cargo/config.toml.
+# It boots the kernel headless under TCG, so it also works in containers
+# without KVM, and turns the isa-debug-exit status into a normal exit code:
+# 33 means every test passed, anything else is a failure. TEST_FILTER only
+# runs the tests with it in their name
+
+set -e
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+kernel="$1"
+
+work="$(mktemp -d)"
+trap 'rm -rf "$work"' EXIT
+
+sysroot="$work/sysroot"
+mkdir -p "$sysroot/EFI/BOOT"
+cp "$root/third-party/limine/limine.sys" "$sysroot/limine.sys"
+cp "$root/third-party/limine/BOOTX64.EFI" "$sysroot/EFI/BOOT/BOOTX64.EFI"
+# Same options as a normal boot, plus `test` for the kernel to know
+sed "s/^KERNEL_CMDLINE=.*/& test${TEST_FILTER:+=$TEST_FILTER}/" \
+	"$root/limine/limine.cfg" >"$sysroot/limine.cfg"
+cp "$kernel" "$sysroot/kernel.elf"
+cp "$root/kernel/user-test/main" "$sysroot/init"
+
+# QEMU writes to the flash image, keep the checked in one untouched
+cp "$root/OVMF.fd" "$work/OVMF.fd"
+
+set +e
+timeout "${TEST_TIMEOUT:-300}" qemu-system-x86_64 \
+	-pflash "$work/OVMF.fd" \
+	-drive file=fat:rw:"$sysroot"/,format=raw \
+	-display none \
+	-serial stdio \
+	-no-reboot \
+	-smp 2 \
+	-m 512M \
+	-device isa-debug-exit,iobase=0xf4,iosize=0x04
+status=$?
+set -e
+
+if [ "$status" -eq 33 ]; then
+	exit 0
+fi
+
+echo "QEMU exited with status $status" >&2
+exit 1
@@ -7,5 +7,6 @@ qemu-system-x86_64 \
 	-nographic \
 	-no-shutdown \
 	-no-reboot \
+	-device isa-debug-exit,iobase=0xf4,iosize=0x04 \
  	-enable-kvm -cpu host \
 